@@ -1,7 +1,7 @@
-use serde::de::{Deserializer, Error, Unexpected, Visitor};
+use serde::de::{Deserializer, Error, MapAccess, Unexpected, Visitor};
 use serde::Serializer;
 use std::fmt::{Formatter, Result as FmtResult};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 pub fn serialize_address<S>(addr: &SocketAddr, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -17,20 +17,182 @@ where
     de.deserialize_any(SocketAddrVisitor)
 }
 
+// 区分缺端口、端口非法、ip 非法等情况, 方便排查 server 返回的异常地址
+pub(crate) fn parse_address(v: &str) -> Result<SocketAddr, String> {
+    let addr = v.trim();
+    if addr.is_empty() {
+        return Err("empty address, expected <ip:port>".to_owned());
+    }
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    let (ip, port) = if let Some(rest) = addr.strip_prefix('[') {
+        match rest.split_once("]:") {
+            Some(pair) => pair,
+            None => return Err(format!("missing port in address `{}`", v)),
+        }
+    } else {
+        match addr.rsplit_once(':') {
+            Some((ip, _)) if ip.contains(':') => {
+                return Err(format!("ipv6 address `{}` must be written as [ip]:port", v));
+            }
+            Some(pair) => pair,
+            None => return Err(format!("missing port in address `{}`", v)),
+        }
+    };
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port `{}` in address `{}`", port, v))?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|_| format!("invalid ip `{}` in address `{}`", ip, v))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
 struct SocketAddrVisitor;
 
 impl<'de> Visitor<'de> for SocketAddrVisitor {
     type Value = SocketAddr;
 
     fn expecting(&self, formatter: &mut Formatter) -> FmtResult {
-        write!(formatter, "<ip:port>")
+        write!(formatter, "<ip:port> or {{\"ip\": <ip>, \"port\": <port>}}")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: Error,
     {
-        v.parse()
-            .map_err(|_| Error::invalid_value(Unexpected::Str(v), &"<ip:port>"))
+        parse_address(v).map_err(Error::custom)
+    }
+
+    // 部分 server 版本以 {"ip": "...", "port": 1234} 形式返回地址
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (mut ip, mut port) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "ip" => {
+                    let v: String = map.next_value()?;
+                    let addr = v
+                        .parse::<IpAddr>()
+                        .map_err(|_| Error::invalid_value(Unexpected::Str(&v), &"<ip>"))?;
+                    ip = Some(addr);
+                }
+                "port" => port = Some(map.next_value::<u16>()?),
+                _ => {
+                    map.next_value::<serde::de::IgnoredAny>()?;
+                }
+            }
+        }
+        let ip = ip.ok_or_else(|| Error::missing_field("ip"))?;
+        let port = port.ok_or_else(|| Error::missing_field("port"))?;
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_address;
+    use crate::service::ServiceEndpoint;
+    use serde_json::json;
+    use std::net::SocketAddr;
+
+    fn decode(address: serde_json::Value) -> Result<SocketAddr, serde_json::Error> {
+        serde_json::from_value::<ServiceEndpoint>(json!({ "address": address, "config": null }))
+            .map(|e| e.address)
+    }
+
+    #[test]
+    fn string_form() {
+        let addr = decode(json!("127.0.0.1:8000")).unwrap();
+        assert_eq!(addr, "127.0.0.1:8000".parse().unwrap());
+    }
+
+    #[test]
+    fn object_form() {
+        let addr = decode(json!({"ip": "127.0.0.1", "port": 8000})).unwrap();
+        assert_eq!(addr, "127.0.0.1:8000".parse().unwrap());
+        // 未知字段忽略
+        let addr = decode(json!({"ip": "10.0.0.1", "port": 80, "weight": 3})).unwrap();
+        assert_eq!(addr, "10.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
+    fn ipv6_forms() {
+        let expected: SocketAddr = "[::1]:8000".parse().unwrap();
+        assert_eq!(decode(json!("[::1]:8000")).unwrap(), expected);
+        assert_eq!(
+            decode(json!({"ip": "::1", "port": 8000})).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn object_form_missing_field() {
+        let err = decode(json!({"ip": "127.0.0.1"})).unwrap_err();
+        assert!(err.to_string().contains("missing field `port`"), "{}", err);
+        let err = decode(json!({"port": 80})).unwrap_err();
+        assert!(err.to_string().contains("missing field `ip`"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_addresses_rejected() {
+        let cases = [
+            ("", "empty address"),
+            ("   ", "empty address"),
+            ("127.0.0.1", "missing port in address `127.0.0.1`"),
+            ("[::1]", "missing port in address `[::1]`"),
+            ("127.0.0.1:", "invalid port `` in address `127.0.0.1:`"),
+            (
+                "127.0.0.1:http",
+                "invalid port `http` in address `127.0.0.1:http`",
+            ),
+            (
+                "127.0.0.1:65536",
+                "invalid port `65536` in address `127.0.0.1:65536`",
+            ),
+            ("[::1]:-1", "invalid port `-1` in address `[::1]:-1`"),
+            (
+                "localhost:80",
+                "invalid ip `localhost` in address `localhost:80`",
+            ),
+            (
+                "300.0.0.1:80",
+                "invalid ip `300.0.0.1` in address `300.0.0.1:80`",
+            ),
+            ("[::zz]:80", "invalid ip `::zz` in address `[::zz]:80`"),
+            (
+                "::1:80",
+                "ipv6 address `::1:80` must be written as [ip]:port",
+            ),
+        ];
+        for (addr, expected) in cases.iter() {
+            let err = parse_address(addr).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", addr, err);
+            // 反序列化报出同样的原因
+            let err = decode(json!(addr)).unwrap_err();
+            assert!(err.to_string().contains(expected), "{:?}: {}", addr, err);
+        }
+    }
+
+    #[test]
+    fn address_whitespace_trimmed() {
+        assert_eq!(
+            parse_address(" 127.0.0.1:80\n").unwrap(),
+            "127.0.0.1:80".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for addr in &["127.0.0.1:8000", "[fe80::1]:443"] {
+            let endpoint = ServiceEndpoint::new(addr.parse().unwrap(), Some("{}".to_owned()));
+            let encoded = serde_json::to_value(&endpoint).unwrap();
+            assert_eq!(encoded["address"], json!(addr));
+            let decoded: ServiceEndpoint = serde_json::from_value(encoded).unwrap();
+            assert_eq!(decoded, endpoint);
+        }
     }
 }
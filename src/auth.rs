@@ -0,0 +1,55 @@
+use crate::error::Error;
+use futures::prelude::*;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Produces a fresh bearer token, e.g. from an OIDC/STS endpoint.
+pub type TokenProvider =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<String, Error>> + Send>> + Send + Sync>;
+
+#[derive(Clone)]
+pub(crate) struct TokenSource {
+    provider: TokenProvider,
+    ttl: Duration,
+    cached: Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+impl TokenSource {
+    pub fn new(provider: TokenProvider, ttl: Duration) -> TokenSource {
+        TokenSource {
+            provider,
+            ttl,
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub async fn token(&self) -> Result<String, Error> {
+        if let Some((token, fetched_at)) = &*self.cached.lock().unwrap() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(token.clone());
+            }
+        }
+        // 保留 provider 的原始错误, 网络类错误仍可重试, 不会让 keeper 取消服务
+        let token = (self.provider)().await.map_err(|e| {
+            warn!(target: "xbus::request", "refresh token fail: {}", e);
+            e
+        })?;
+        *self.cached.lock().unwrap() = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn provider_error_stays_retryable() {
+        let provider: TokenProvider = Arc::new(|| future::err(Error::io_timeout()).boxed());
+        let source = TokenSource::new(provider, Duration::from_secs(60));
+        let err = source.token().await.unwrap_err();
+        assert!(err.is_timeout());
+        assert!(err.can_retry());
+    }
+}
@@ -0,0 +1,60 @@
+use crate::random_u64;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Retry timing of watches, lease grants and replugs.
+pub trait Backoff: Send {
+    /// Delay before the `attempt`th consecutive retry, counting from 1.
+    fn next_delay(&mut self, attempt: u32) -> Duration;
+}
+
+/// Creates a fresh `Backoff` for each retry loop.
+pub type BackoffFactory = Arc<dyn Fn() -> Box<dyn Backoff> + Send + Sync>;
+
+/// Doubles from `base` up to `max`, minus a random jitter of up to half the
+/// delay so that clients don't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct ExponentialBackoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl ExponentialBackoff {
+    pub fn new(base: Duration, max: Duration) -> ExponentialBackoff {
+        ExponentialBackoff { base, max }
+    }
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> ExponentialBackoff {
+        ExponentialBackoff::new(Duration::from_secs(5), Duration::from_secs(120))
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .base
+            .checked_mul(1 << exp)
+            .map_or(self.max, |d| d.min(self.max));
+        delay - jitter(delay / 2)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConstantBackoff(pub Duration);
+
+impl Backoff for ConstantBackoff {
+    fn next_delay(&mut self, _attempt: u32) -> Duration {
+        self.0
+    }
+}
+
+pub(crate) fn jitter(max: Duration) -> Duration {
+    if max == Duration::from_secs(0) {
+        return max;
+    }
+    let nanos = random_u64() % (max.as_nanos() as u64).max(1);
+    Duration::from_nanos(nanos)
+}
@@ -1,23 +1,62 @@
+use crate::auth::{TokenProvider, TokenSource};
+use crate::connector::{ConnStats, Connector, HostOverrides, UNIX_HTTP_ENDPOINT, UNIX_SCHEME};
+use crate::error::Error;
 use crate::https::{HttpsConnector, TlsClientConfigExt};
-use crate::request::{Form, RequestBuilder};
-use crate::service::{AppNode, AppNodes, Service, ServiceDesc, ServiceEndpoint};
+use crate::request::{Form, RequestBuilder, RequestLimiter};
+use crate::service::{check_endpoints, AppNode, AppNodes, Service, ServiceDesc, ServiceEndpoint};
+use crate::service_cache::ServiceCache;
 use crate::service_keeper::ServiceKeeper;
-use crate::watcher::{WatchStream, WatchTask};
-use crate::{config::Config, service::ServiceDescEvent};
-use crate::{error::Error, RevisionResult};
+use crate::single_flight::SingleFlight;
+#[cfg(all(unix, feature = "unix-socket"))]
+use crate::unix::UnixConnector;
+use crate::watcher::{WatchRegistry, WatchStream, WatchTask};
+use crate::{config::Config, service::ServiceDescEvent, RevisionResult};
 use futures::prelude::*;
+use http::header::{HeaderValue, USER_AGENT};
+use hyper::client::connect::Connection;
 use hyper::client::{Client as HttpClient, HttpConnector};
-use hyper::Method;
+use hyper::service::Service as HttpService;
+use hyper::{Method, Uri};
 use serde::Deserialize;
 use serde_json;
-use serde_yaml;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+
+pub use crate::results::*;
+
+const DEFAULT_USER_AGENT: &str = concat!("xbus-rs/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Clone)]
 pub struct Client {
     app_name: Option<String>,
+    // with_app_name 的覆盖值, 不写入 config.dev_app, 否则与证书配置冲突
+    app_override: Option<String>,
+    endpoint: String,
     config: Config,
-    client: HttpClient<HttpsConnector<HttpConnector>>,
+    client: HttpClient<Connector>,
+    config_flight: SingleFlight<Item>,
+    token_source: Option<TokenSource>,
+    limiter: RequestLimiter,
+    connector: Connector,
+    // with_connector 传入的 connector, clone_with_config 时不能换成内置的 https connector
+    custom_connector: bool,
+    user_agent: HeaderValue,
+    watches: WatchRegistry,
+}
+
+// 只输出 endpoint 与 app_name, 证书路径与 token 不外泄
+impl Debug for Client {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Client")
+            .field("endpoint", &self.endpoint)
+            .field("app_name", &self.app_name)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Client {
@@ -25,12 +64,23 @@ impl Client {
 
     fn build_https_connector(
         config: &Config,
-    ) -> Result<(HttpsConnector<HttpConnector>, Option<String>), Error> {
+    ) -> Result<(HttpsConnector<HostOverrides<HttpConnector>>, Option<String>), Error> {
         let mut tls_config = ::rustls::ClientConfig::new();
         if config.insecure {
-            warn!("using insecure https client");
+            if cfg!(not(feature = "dangerous-insecure")) {
+                return Err(Error::Ssl(
+                    "insecure tls is disabled by the dangerous-insecure feature".to_string(),
+                ));
+            }
+            warn!(target: "xbus::tls", "using insecure https client");
             tls_config.set_insecure();
         }
+        if let Some(min) = config.min_tls_version {
+            if !tls_config.supports_version(min) {
+                return Err(Error::Ssl(format!("unsupported tls version: {:?}", min)));
+            }
+            tls_config.versions.retain(|v| v.get_u16() >= min.get_u16());
+        }
         config.add_ca(&mut tls_config.root_store)?;
         let app_name = if let Some((cert, key)) = config.load_cert_key()? {
             Some(tls_config.add_cert_key(cert, key)?)
@@ -40,15 +90,111 @@ impl Client {
 
         let mut http_connector = HttpConnector::new();
         http_connector.enforce_http(false);
+        if let Some(nodelay) = config.tcp_nodelay {
+            http_connector.set_nodelay(nodelay);
+        }
+        if let Some(idle) = config.tcp_keepalive {
+            http_connector.set_keepalive(Some(idle));
+        }
+        let http_connector = HostOverrides::new(http_connector, config.host_overrides.clone());
         let https_connector = HttpsConnector::new(tls_config, http_connector);
         Ok((https_connector, app_name))
     }
 
-    pub fn new(config: Config) -> Result<Client, Error> {
+    fn check_identity(config: &Config) -> Result<(), Error> {
         if config.dev_app.is_some() && config.cert_key_file.is_some() {
             return Err(Error::Other("dev_app & config duplicated".to_string()));
         }
-        let (https_connector, mut app_name) = Self::build_https_connector(&config)?;
+        Ok(())
+    }
+
+    pub fn new(config: Config) -> Result<Client, Error> {
+        Self::check_identity(&config)?;
+        if let Some(path) = config.endpoint.strip_prefix(UNIX_SCHEME) {
+            return Self::unix_client(config.clone(), path);
+        }
+        let (https_connector, app_name) = Self::build_https_connector(&config)?;
+        if app_name.is_none() && config.dev_app.is_none() && config.endpoint.starts_with("https") {
+            if config.strict_identity {
+                return Err(Error::Other(
+                    "neither client cert nor dev_app configured".to_string(),
+                ));
+            }
+            static WARN: Once = Once::new();
+            WARN.call_once(|| {
+                warn!(
+                    target: "xbus::tls",
+                    "neither client cert nor dev_app configured, \
+                     authenticated requests will be rejected"
+                );
+            });
+        }
+        Self::from_connector(config, Connector::new(https_connector), app_name)
+    }
+
+    /// Like `new`, but contacts the server first, so that an unreachable endpoint,
+    /// a TLS failure or a rejected client identity shows up here instead of on
+    /// first use. With an app name, from the cert or `dev_app`, the identity is
+    /// checked by reading the app's own nodes; without one, the server is pinged.
+    pub async fn connect(config: Config) -> Result<Client, Error> {
+        Client::new(config)?.probe().await
+    }
+
+    async fn probe(self) -> Result<Client, Error> {
+        let app = match self.app_name {
+            Some(ref app) => app.clone(),
+            None => {
+                self.ping().await?;
+                return Ok(self);
+            }
+        };
+        // 空的 get_all 不校验身份, 读取自身 app 的节点时服务端会校验
+        match self.get_app_nodes(&app, None).await {
+            Ok(_) => Ok(self),
+            Err(Error::NotPermitted(message, _)) => Err(Error::Connect(format!(
+                "client identity {} rejected: {}",
+                app, message
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(all(unix, feature = "unix-socket"))]
+    fn unix_client(config: Config, path: &str) -> Result<Client, Error> {
+        let connector = Connector::new(UnixConnector::new(path));
+        Self::from_connector(config, connector, None)
+    }
+
+    #[cfg(not(all(unix, feature = "unix-socket")))]
+    fn unix_client(_config: Config, _path: &str) -> Result<Client, Error> {
+        Err(Error::Other(
+            "unix socket endpoint requires the unix-socket feature".to_string(),
+        ))
+    }
+
+    /// Builds a client on a custom transport instead of the built-in rustls connector.
+    ///
+    /// `connector` is a `Service<Uri>` as accepted by hyper: it must be `Clone + Send + Sync`,
+    /// resolve to an `AsyncRead + AsyncWrite + Connection + Send + Unpin` stream, and fail
+    /// with an error convertible into `Box<dyn std::error::Error + Send + Sync>`.
+    /// TLS settings in `config` are ignored, so the app name only comes from `dev_app`.
+    pub fn with_connector<C>(config: Config, connector: C) -> Result<Client, Error>
+    where
+        C: HttpService<Uri> + Clone + Send + Sync + 'static,
+        C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut client = Self::from_connector(config, Connector::new(connector), None)?;
+        client.custom_connector = true;
+        Ok(client)
+    }
+
+    pub(crate) fn from_connector(
+        config: Config,
+        connector: Connector,
+        mut app_name: Option<String>,
+    ) -> Result<Client, Error> {
         if config.dev_app.is_some() {
             app_name = config.dev_app.clone();
         }
@@ -57,64 +203,453 @@ impl Client {
             .unwrap_or(Self::DEFAULT_MAX_IDLE_PER_HOST);
         let client = HttpClient::builder()
             .pool_max_idle_per_host(max_idle_per_host)
-            .build(https_connector);
+            .build(connector.clone());
+        let mut endpoint = if config.endpoint.starts_with(UNIX_SCHEME) {
+            UNIX_HTTP_ENDPOINT.to_string()
+        } else {
+            config.endpoint.clone()
+        };
+        // 请求路径都以 / 开头, base path 规整为 /xxx 形式拼到 endpoint 后
+        let base_path = config.base_path.as_deref().unwrap_or("").trim_matches('/');
+        if !base_path.is_empty() {
+            endpoint = format!("{}/{}", endpoint.trim_end_matches('/'), base_path);
+        }
+        let limiter = RequestLimiter::new(config.max_concurrent_requests);
+        let user_agent = match config.user_agent {
+            Some(ref ua) => HeaderValue::from_str(ua)
+                .map_err(|_| Error::Other(format!("invalid user agent: {}", ua)))?,
+            None => HeaderValue::from_static(DEFAULT_USER_AGENT),
+        };
         Ok(Client {
             app_name,
+            app_override: None,
+            endpoint,
             config,
             client,
+            config_flight: SingleFlight::new(),
+            token_source: None,
+            limiter,
+            connector,
+            custom_connector: false,
+            user_agent,
+            watches: WatchRegistry::default(),
         })
     }
 
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn get_app_name(&self) -> Option<&str> {
         self.app_name.as_deref()
     }
 
+    /// The endpoint as configured, without `base_path`.
+    pub fn endpoint(&self) -> &str {
+        &self.config.endpoint
+    }
+
+    /// `Config::request_timeout`, `request_timeout` being taken by the request builder.
+    pub fn default_request_timeout(&self) -> Duration {
+        self.config.request_timeout
+    }
+
+    pub fn is_insecure(&self) -> bool {
+        self.config.insecure
+    }
+
+    /// Returns a clone acting as `name`, sent as `Dev-App` regardless of the cert CN.
+    pub fn with_app_name(mut self, name: impl Into<String>) -> Client {
+        let name = name.into();
+        self.app_name = Some(name.clone());
+        self.app_override = Some(name);
+        // 身份不同, 不能共享其他 app 发起的 get_coalesced 结果
+        self.config_flight = SingleFlight::new();
+        self
+    }
+
+    /// Derives a client with a modified copy of the config. The connector, and so
+    /// the TLS setup, is reused unless the change affects it (endpoint, CA, cert,
+    /// TLS version, `insecure`, TCP options, host overrides, or dropping `dev_app`), in which case
+    /// the client is rebuilt as by `Client::new`. A connector given to `with_connector`
+    /// is always reused. The derived client has its own connection pool and keeps
+    /// the app name set by `with_app_name` and the token provider.
+    pub fn clone_with_config(&self, f: impl FnOnce(&mut Config)) -> Result<Client, Error> {
+        let mut config = self.config.clone();
+        f(&mut config);
+        Self::check_identity(&config)?;
+        let old = &self.config;
+        let rebuild = config.endpoint != old.endpoint
+            || config.insecure != old.insecure
+            || config.ca_file != old.ca_file
+            || config.cert_key_file != old.cert_key_file
+            || config.min_tls_version != old.min_tls_version
+            || config.tcp_nodelay != old.tcp_nodelay
+            || config.tcp_keepalive != old.tcp_keepalive
+            || config.host_overrides != old.host_overrides
+            || (old.dev_app.is_some() && config.dev_app.is_none());
+        let mut client = if rebuild && !self.custom_connector {
+            Client::new(config)?
+        } else {
+            // 自定义 connector 不加载证书, app name 只来自 dev_app
+            let app_name = if self.custom_connector {
+                None
+            } else {
+                self.app_name.clone()
+            };
+            Self::from_connector(config, self.connector.clone(), app_name)?
+        };
+        client.custom_connector = self.custom_connector;
+        client.token_source = self.token_source.clone();
+        if let Some(ref name) = self.app_override {
+            client = client.with_app_name(name.clone());
+        }
+        Ok(client)
+    }
+
+    /// Fetches the bearer token from `provider` before requests, reusing it for `ttl`.
+    /// Takes precedence over `Config::bearer_token`.
+    pub fn with_token_provider(mut self, provider: TokenProvider, ttl: Duration) -> Client {
+        self.token_source = Some(TokenSource::new(provider, ttl));
+        self.config_flight = SingleFlight::new();
+        self
+    }
+
+    /// The underlying http client sharing this client's TLS setup and connection pool.
+    /// Requests issued through it bypass `RequestBuilder`, so callers must handle the
+    /// xbus response envelope themselves.
+    pub fn http_client(&self) -> &HttpClient<Connector> {
+        &self.client
+    }
+
+    /// `path` may be borrowed or owned, e.g. a `format!`ed string.
     pub fn request<'a>(
         &'a self,
         method: Method,
-        path: &'a str,
-    ) -> RequestBuilder<'a, HttpsConnector<HttpConnector>> {
+        path: impl Into<Cow<'a, str>>,
+    ) -> RequestBuilder<'a, Connector> {
         self.request_timeout(method, path, self.config.request_timeout)
     }
 
     pub fn request_timeout<'a>(
         &'a self,
         method: Method,
-        path: &'a str,
+        path: impl Into<Cow<'a, str>>,
         timeout: Duration,
-    ) -> RequestBuilder<'a, HttpsConnector<HttpConnector>> {
-        let mut builder = RequestBuilder::new(
-            &self.client,
-            &self.config.endpoint,
-            method,
-            path,
-            Some(timeout),
-        );
-        if let Some(ref dev_app) = self.config.dev_app {
+    ) -> RequestBuilder<'a, Connector> {
+        let mut builder =
+            RequestBuilder::new(&self.client, &self.endpoint, method, path, Some(timeout))
+                .error_body_limit(self.config.error_body_limit)
+                .max_response_bytes(self.config.max_response_bytes)
+                .compress(self.config.compress_requests)
+                .header_value(USER_AGENT, self.user_agent.clone());
+        if let Some(dev_app) = self.app_override.as_ref().or(self.config.dev_app.as_ref()) {
             builder = builder.header("Dev-App", dev_app);
         }
+        if let Some(ref token) = self.config.bearer_token {
+            builder = builder.bearer_auth(token);
+        }
+        if let Some(ref source) = self.token_source {
+            builder = builder.token_source(source.clone());
+        }
         builder
+            .limiter(Some(self.limiter.clone()))
+            .slow_threshold(self.config.slow_request_threshold)
+    }
+
+    // long poll 不受并发限制也不算慢请求, 以免 watch 占满名额
+    fn long_poll_request<'a>(
+        &'a self,
+        method: Method,
+        path: impl Into<Cow<'a, str>>,
+        timeout: Duration,
+    ) -> RequestBuilder<'a, Connector> {
+        self.request_timeout(method, path, timeout)
+            .limiter(None)
+            .slow_threshold(None)
+    }
+
+    /// Closes every watch spawned from this client or its clones, e.g. on
+    /// shutdown; returns how many were still running. Their streams end as if
+    /// each `WatchHandle` were dropped, which remains the way to close a single
+    /// watch. Watches started afterwards are not affected.
+    pub fn shutdown_watches(&self) -> usize {
+        self.watches.close_all()
+    }
+
+    /// Number of requests currently being sent, long polls excluded.
+    pub fn in_flight_requests(&self) -> usize {
+        self.limiter.in_flight()
+    }
+
+    pub fn connection_stats(&self) -> ConnStats {
+        self.connector.stats()
     }
 
     pub fn get(&self, key: &str) -> impl Future<Output = Result<Item, Error>> {
-        self.request(Method::GET, &format!("/api/configs/{}", key))
+        self.request(Method::GET, format!("/api/configs/{}", key))
             .send::<ItemResult>()
             .map(|result| result.map(|r| r.config))
     }
 
+    /// Like `get`, but concurrent calls for the same key share one request.
+    /// Only calls through this client and its plain clones are shared, not
+    /// ones made under another identity by `with_app_name` or `with_token_provider`.
+    pub fn get_coalesced(&self, key: &str) -> impl Future<Output = Result<Item, Error>> {
+        self.config_flight
+            .call(format!("GET /api/configs/{}", key), || self.get(key))
+    }
+
+    /// Writes `value` to `key` and returns the config's new version, the
+    /// revision of the write. With a `version`, the server only accepts the
+    /// write if the config is still at that version.
+    pub fn put_config(
+        &self,
+        key: &str,
+        value: &str,
+        version: Option<u64>,
+    ) -> impl Future<Output = Result<u64, Error>> {
+        self.request(Method::PUT, format!("/api/configs/{}", key))
+            .form_result(form!("value" => value, "version" => version))
+            .send::<ConfigPutResult>()
+            .map_ok(|r| r.revision)
+    }
+
+    /// Writes `value` only if `key` is still at `expected_version` and returns
+    /// the config as written. Fails with `Error::VersionConflict` if the server
+    /// rejects the version, carrying the version read right after.
+    pub async fn compare_and_set(
+        &self,
+        key: &str,
+        expected_version: u64,
+        value: &str,
+    ) -> Result<Item, Error> {
+        match self.put_config(key, value, Some(expected_version)).await {
+            Ok(version) => Ok(Item::new(key, value, version)),
+            Err(e) if e.is_version_mismatch() => {
+                let current = self.get(key).await?.version;
+                Err(Error::VersionConflict { current })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn get_all(&self, keys: &[String]) -> Result<Vec<Item>, Error> {
+        Ok(self.get_all_with_revision(keys).await?.configs)
+    }
+
+    /// Round-trip time of a minimal request, an empty `get_all`. Success shows
+    /// the endpoint is reachable, TLS is accepted and the server answers; it
+    /// does not prove the client's identity or permissions are accepted, as
+    /// an empty key list may be answered before any such check.
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let start = Instant::now();
+        self.get_all(&[]).await?;
+        Ok(start.elapsed())
+    }
+
+    async fn get_all_with_revision(&self, keys: &[String]) -> Result<ItemsResult, Error> {
         let val = serde_json::to_string(keys).map_err(Error::from)?;
-        let result = self
-            .request(Method::GET, "/api/configs")
+        self.request(Method::GET, "/api/configs")
             .param("keys", &val)
             .send::<ItemsResult>()
-            .await?;
-        Ok(result.configs)
+            .await
+    }
+
+    /// One page of configs whose name starts with `prefix`, in name order,
+    /// starting after the config named `after`.
+    pub async fn list_configs(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Item>, Error> {
+        Ok(self
+            .request(Method::GET, "/api/configs")
+            .param("prefix", prefix)
+            .param_opt("after", after)
+            .param("limit", &limit.to_string())
+            .send::<ItemsResult>()
+            .await?
+            .configs)
     }
 
-    pub fn get_service(&self, service: &str) -> impl Future<Output = Result<ServiceResult, Error>> {
-        self.request(Method::GET, &format!("/api/v1/services/{}", service))
+    /// Follows the `after` cursor of `list_configs`, fetching the next page only
+    /// once the previous one is consumed. Ends at the first empty page or error.
+    pub fn list_configs_stream(
+        &self,
+        prefix: &str,
+        page_size: usize,
+    ) -> impl Stream<Item = Result<Item, Error>> {
+        let (client, prefix) = (self.clone(), prefix.to_owned());
+        // 状态为 None 时已结束, Some(after) 为下一页的游标
+        stream::unfold(Some(None), move |cursor: Option<Option<String>>| {
+            let (client, prefix) = (client.clone(), prefix.clone());
+            async move {
+                let after = cursor?;
+                match client
+                    .list_configs(&prefix, after.as_deref(), page_size)
+                    .await
+                {
+                    Ok(items) if items.is_empty() => None,
+                    Ok(items) => {
+                        let next = items.last().map(|item| item.name.clone());
+                        Some((Ok(items), Some(next)))
+                    }
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        })
+        .flat_map(|page| match page {
+            Ok(items) => stream::iter(items.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(e) => stream::iter(vec![Err(e)]),
+        })
+    }
+
+    /// Yields only the configs changed after `revision`, `None` on timeout.
+    pub async fn watch_configs_once(
+        &self,
+        keys: &[String],
+        revision: u64,
+        timeout: Duration,
+    ) -> Result<Option<ItemsResult>, Error> {
+        let val = serde_json::to_string(keys).map_err(Error::from)?;
+        let result = self
+            .long_poll_request(
+                Method::GET,
+                "/api/configs",
+                timeout + self.config.watch_request_overhead,
+            )
+            .param("keys", &val)
+            .param("watch", "true")
+            .param("revision", &format!("{}", revision))
+            .param("timeout", &format!("{}", timeout.as_secs()))
             .send()
+            .await;
+        match result {
+            Ok(r) => Ok(Some(r)),
+            Err(e) => {
+                if e.is_timeout() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Watches a set of keys in one long poll; the first item is the full
+    /// snapshot, later ones only the changed subset.
+    pub fn watch_configs(
+        &self,
+        keys: &[String],
+        revision: Option<u64>,
+        interval: Duration,
+    ) -> WatchStream<ItemsResult> {
+        let client = self.clone();
+        let keys = keys.to_vec();
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            revision,
+            move |revision| {
+                let (client, keys) = (client.clone(), keys.clone());
+                match revision {
+                    Some(revision) => async move {
+                        client
+                            .watch_configs_once(&keys, revision + 1, interval)
+                            .await
+                    }
+                    .boxed(),
+                    None => {
+                        async move { client.get_all_with_revision(&keys).await.map(Some) }.boxed()
+                    }
+                }
+            },
+        )
+    }
+
+    /// With a `label`, only endpoints tagged with it are returned.
+    pub fn get_service(
+        &self,
+        service: &str,
+        label: Option<&str>,
+    ) -> impl Future<Output = Result<ServiceResult, Error>> {
+        self.request(Method::GET, format!("/api/v1/services/{}", service))
+            .param_opt("label", label)
+            .send()
+    }
+
+    /// The service with unhealthy endpoints filtered out, see `ServiceEndpoint::is_healthy`.
+    pub fn get_healthy_service(
+        &self,
+        service: &str,
+    ) -> impl Future<Output = Result<Service, Error>> {
+        self.get_service_filtered(service, ServiceEndpoint::is_healthy)
+    }
+
+    /// The service keeping only the endpoints accepted by `healthy`.
+    pub fn get_service_filtered<F>(
+        &self,
+        service: &str,
+        healthy: F,
+    ) -> impl Future<Output = Result<Service, Error>>
+    where
+        F: Fn(&ServiceEndpoint) -> bool + Send + 'static,
+    {
+        self.get_service(service, None).map(move |result| {
+            result.map(|r| {
+                let mut service = r.service;
+                service.retain_endpoints(&healthy);
+                service
+            })
+        })
+    }
+
+    pub fn get_services(
+        &self,
+        names: &[&str],
+    ) -> impl Future<Output = Result<HashMap<String, Service>, Error>> {
+        if self.config.batch_services {
+            let val = match serde_json::to_string(names) {
+                Ok(val) => val,
+                Err(e) => return future::err(Error::from(e)).boxed(),
+            };
+            return self
+                .request(Method::GET, "/api/v1/services")
+                .param("services", &val)
+                .send::<ServicesResult>()
+                .map(|result| result.map(|r| r.services))
+                .boxed();
+        }
+        // 按请求的名字作 key, 服务端规范化后的名字可能与之不同
+        let keys: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        future::try_join_all(names.iter().map(|name| self.get_service(name, None)))
+            .map(move |result| {
+                result.map(|results| {
+                    keys.into_iter()
+                        .zip(results)
+                        .map(|(name, r)| (name, r.service))
+                        .collect()
+                })
+            })
+            .boxed()
+    }
+
+    /// Returns `None` if the service hasn't changed since `since_revision`.
+    pub fn get_service_if_changed(
+        &self,
+        service: &str,
+        since_revision: u64,
+    ) -> impl Future<Output = Result<Option<ServiceResult>, Error>> {
+        // entity tag 按 RFC 7232 加引号, 否则遵循规范的代理永远不会返回 304
+        let etag = format!("\"{}\"", since_revision);
+        self.request(Method::GET, format!("/api/v1/services/{}", service))
+            .header("If-None-Match", &etag)
+            .get_option::<ServiceResult>()
+            .map(move |result| {
+                result.map(|r| r.filter(|r| r.revision == 0 || r.revision > since_revision))
+            })
     }
 
     pub fn get_service_only_zones(
@@ -123,7 +658,7 @@ impl Client {
     ) -> impl Future<Output = Result<ServiceWithRawZoneResult, Error>> {
         self.request(
             Method::GET,
-            &format!("/api/v1/services/{}?only_zone=true", service),
+            format!("/api/v1/services/{}?only_zone=true", service),
         )
         .send()
     }
@@ -135,7 +670,7 @@ impl Client {
     ) -> impl Future<Output = Result<ServiceResult, Error>> {
         self.request(
             Method::GET,
-            &format!("/api/v1/services/{}/{}", service, zone),
+            format!("/api/v1/services/{}/{}", service, zone),
         )
         .send()
     }
@@ -151,7 +686,7 @@ impl Client {
                          "desc" => service, "endpoint" => endpoint);
         self.request(
             Method::POST,
-            &format!("/api/v1/services/{}", &service.service),
+            format!("/api/v1/services/{}", &service.service),
         )
         .form_result(form)
         .send()
@@ -171,6 +706,26 @@ impl Client {
             .send()
     }
 
+    /// Like `plug_all_services`, for a node with several endpoints each carrying
+    /// its own config (e.g. weights); addresses must be unique.
+    pub fn plug_all_services_with_endpoints(
+        &self,
+        services: &[ServiceDesc],
+        endpoints: &[ServiceEndpoint],
+        lease_id: Option<i64>,
+        ttl: Option<i64>,
+    ) -> impl Future<Output = Result<PlugResult, Error>> {
+        if let Err(e) = check_endpoints(endpoints) {
+            return future::err(e).boxed();
+        }
+        let form = form!("ttl" => ttl, "lease_id" => lease_id,
+                         "descs" => services, "endpoints" => endpoints);
+        self.request(Method::POST, "/api/v1/services")
+            .form_result(form)
+            .send()
+            .boxed()
+    }
+
     pub fn unplug_service(
         &self,
         service: &str,
@@ -179,11 +734,31 @@ impl Client {
     ) -> impl Future<Output = Result<(), Error>> {
         self.request(
             Method::DELETE,
-            &format!("/api/v1/services/{}/{}/{}", service, zone, addr),
+            format!("/api/v1/services/{}/{}/{}", service, zone, addr),
         )
         .get_ok()
     }
 
+    /// Grants a lease and plugs `services` under it, for short-lived processes
+    /// that don't need a `ServiceKeeper`. The lease is never renewed: the
+    /// services disappear after `ttl`, or when the returned guard is dropped.
+    pub async fn register_once(
+        &self,
+        services: &[ServiceDesc],
+        endpoint: &ServiceEndpoint,
+        ttl: Option<i64>,
+    ) -> Result<LeaseGuard, Error> {
+        let lease = self.grant_lease(ttl, None).await?;
+        let guard = LeaseGuard {
+            client: self.clone(),
+            lease_id: Some(lease.lease_id),
+        };
+        // plug 失败时 guard 在此 drop, 撤销刚申请的 lease
+        self.plug_all_services(services, endpoint, Some(lease.lease_id), None)
+            .await?;
+        Ok(guard)
+    }
+
     pub fn grant_lease(
         &self,
         ttl: Option<i64>,
@@ -202,12 +777,36 @@ impl Client {
     }
 
     pub fn keepalive_lease(&self, lease_id: i64) -> impl Future<Output = Result<(), Error>> {
-        self.request(Method::POST, &format!("/api/leases/{}", lease_id))
+        self.request(Method::POST, format!("/api/leases/{}", lease_id))
             .get_ok()
     }
 
+    /// Like `keepalive_lease`, also returning the lease's current TTL if the server reports it.
+    /// Fails with `Error::LeaseExpired` if the lease is gone, e.g. after a long suspend.
+    pub fn keepalive_lease_with_result(
+        &self,
+        lease_id: i64,
+    ) -> impl Future<Output = Result<Option<LeaseGrantResult>, Error>> {
+        self.request(Method::POST, format!("/api/leases/{}", lease_id))
+            .get_option()
+            .map(move |result| match result {
+                Err(e) if e.is_not_found() => Err(Error::LeaseExpired(lease_id)),
+                r => r,
+            })
+    }
+
+    /// Fails with `Error::LeaseExpired` if the lease no longer exists.
+    pub fn get_lease(&self, lease_id: i64) -> impl Future<Output = Result<LeaseInfo, Error>> {
+        self.request(Method::GET, format!("/api/leases/{}", lease_id))
+            .send()
+            .map(move |result| match result {
+                Err(e) if e.is_not_found() => Err(Error::LeaseExpired(lease_id)),
+                r => r,
+            })
+    }
+
     pub fn revoke_lease(&self, lease_id: i64) -> impl Future<Output = Result<(), Error>> {
-        self.request(Method::DELETE, &format!("/api/leases/{}", lease_id))
+        self.request(Method::DELETE, format!("/api/leases/{}", lease_id))
             .get_ok()
     }
 
@@ -217,18 +816,31 @@ impl Client {
         key: &str,
         label: Option<&str>,
     ) -> impl Future<Output = Result<(), Error>> {
-        self.request(Method::DELETE, &format!("/api/leases/{}", lease_id))
+        self.request(Method::DELETE, format!("/api/leases/{}", lease_id))
             .param("rm_node_key", key)
             .param_opt("app_node_label", label)
             .get_ok()
     }
 
+    /// Removes the app node along with every lease attached to it.
+    pub fn revoke_node(
+        &self,
+        app: &str,
+        key: &str,
+        label: Option<&str>,
+    ) -> impl Future<Output = Result<(), Error>> {
+        self.request(Method::DELETE, format!("/api/apps/{}/nodes", app))
+            .param("key", key)
+            .param_opt("label", label)
+            .get_ok()
+    }
+
     pub fn get_app_nodes(
         &self,
         name: &str,
         label: Option<&str>,
     ) -> impl Future<Output = Result<AppNodes, Error>> {
-        self.request(Method::GET, &format!("/api/apps/{}/nodes", name))
+        self.request(Method::GET, format!("/api/apps/{}/nodes", name))
             .param_opt("label", label)
             .send()
     }
@@ -240,10 +852,10 @@ impl Client {
         revision: u64,
         timeout: Duration,
     ) -> impl Future<Output = Result<Option<AppNodes>, Error>> {
-        self.request_timeout(
+        self.long_poll_request(
             Method::GET,
-            &format!("/api/apps/{}/nodes", app),
-            timeout + self.config.request_timeout,
+            format!("/api/apps/{}/nodes", app),
+            timeout + self.config.watch_request_overhead,
         )
         .param_opt("label", label)
         .param("revision", &format!("{}", revision))
@@ -270,18 +882,23 @@ impl Client {
         let client = self.clone();
         let app = app.to_string();
         let label = label.map(|s| s.to_string());
-        WatchTask::spawn(None, move |revision| {
-            let label: Option<&str> = label.as_deref();
-            match revision {
-                Some(revision) => client
-                    .watch_app_nodes_once(&app, label, revision + 1, timeout)
-                    .boxed(),
-                None => client
-                    .get_app_nodes(&app, label)
-                    .map(|r| r.map(Some))
-                    .boxed(),
-            }
-        })
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            None,
+            move |revision| {
+                let label: Option<&str> = label.as_deref();
+                match revision {
+                    Some(revision) => client
+                        .watch_app_nodes_once(&app, label, revision + 1, timeout)
+                        .boxed(),
+                    None => client
+                        .get_app_nodes(&app, label)
+                        .map(|r| r.map(Some))
+                        .boxed(),
+                }
+            },
+        )
     }
 
     pub fn is_app_node_online(
@@ -290,7 +907,7 @@ impl Client {
         label: Option<&str>,
         key: &str,
     ) -> impl Future<Output = Result<bool, Error>> {
-        self.request(Method::GET, &format!("/api/apps/{}/online", app))
+        self.request(Method::GET, format!("/api/apps/{}/online", app))
             .param_opt("label", label)
             .param("key", key)
             .send()
@@ -299,14 +916,16 @@ impl Client {
     pub fn watch_service_once(
         &self,
         service: &str,
+        label: Option<&str>,
         revision: u64,
         timeout: Duration,
     ) -> impl Future<Output = Result<Option<ServiceResult>, Error>> {
-        self.request_timeout(
+        self.long_poll_request(
             Method::GET,
-            &format!("/api/v1/services/{}", service),
-            timeout + self.config.request_timeout,
+            format!("/api/v1/services/{}", service),
+            timeout + self.config.watch_request_overhead,
         )
+        .param_opt("label", label)
         .param("watch", "true")
         .param("revision", &format!("{}", revision))
         .param("timeout", &format!("{}", timeout.as_secs()))
@@ -328,37 +947,230 @@ impl Client {
         service: &str,
         zone: Option<&str>,
     ) -> impl Future<Output = Result<(), Error>> {
-        self.request(Method::DELETE, &format!("/api/v1/services/{}", service))
+        self.request(Method::DELETE, format!("/api/v1/services/{}", service))
             .param("zone", zone.unwrap_or(""))
             .get_ok()
     }
 
+    /// Reports what `delete_service` would remove without deleting anything.
+    /// Sent as a GET, so a server without dry-run support can't delete by mistake
+    /// and its reply is rejected instead.
+    pub fn delete_service_dry_run(
+        &self,
+        service: &str,
+        zone: Option<&str>,
+    ) -> impl Future<Output = Result<DeleteServiceSummary, Error>> {
+        self.request(Method::GET, format!("/api/v1/services/{}", service))
+            .param("zone", zone.unwrap_or(""))
+            .param("delete_dry_run", "true")
+            .send::<DeleteDryRunResult>()
+            .map(|result| {
+                result?.dry_run.ok_or_else(|| {
+                    Error::Other("server does not support dry-run service deletion".to_owned())
+                })
+            })
+    }
+
     pub fn watch_service(
         &self,
         service: &str,
+        label: Option<&str>,
         revision: Option<u64>,
         interval: Duration,
     ) -> WatchStream<ServiceResult> {
+        self.watch_service_with(service, label, revision, interval, |r| r)
+    }
+
+    /// Like `watch_service`, sharing each snapshot between consumers instead of
+    /// cloning it, for large services fanned out to many tasks.
+    pub fn watch_service_shared(
+        &self,
+        service: &str,
+        label: Option<&str>,
+        revision: Option<u64>,
+        interval: Duration,
+    ) -> WatchStream<Arc<ServiceResult>> {
+        self.watch_service_with(service, label, revision, interval, Arc::new)
+    }
+
+    fn watch_service_with<T>(
+        &self,
+        service: &str,
+        label: Option<&str>,
+        revision: Option<u64>,
+        interval: Duration,
+        wrap: fn(ServiceResult) -> T,
+    ) -> WatchStream<T>
+    where
+        T: RevisionResult + Send + 'static,
+    {
         let client = self.clone();
         let service = service.to_string();
-        WatchTask::spawn(revision, move |revision| match revision {
-            Some(revision) => client
-                .watch_service_once(&service, revision + 1, interval)
-                .boxed(),
-            None => client
-                .get_service(&service)
-                .map(|result| result.map(Some))
-                .boxed(),
+        let label = label.map(|s| s.to_string());
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            revision,
+            move |revision| {
+                let label: Option<&str> = label.as_deref();
+                match revision {
+                    Some(revision) => client
+                        .watch_service_once(&service, label, revision + 1, interval)
+                        .map_ok(move |r| r.map(wrap))
+                        .boxed(),
+                    None => client
+                        .get_service(&service, label)
+                        .map_ok(move |r| Some(wrap(r)))
+                        .boxed(),
+                }
+            },
+        )
+    }
+
+    pub fn watch_service_by_zone_once(
+        &self,
+        service: &str,
+        zone: &str,
+        revision: u64,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Option<ServiceResult>, Error>> {
+        self.long_poll_request(
+            Method::GET,
+            format!("/api/v1/services/{}/{}", service, zone),
+            timeout + self.config.watch_request_overhead,
+        )
+        .param("watch", "true")
+        .param("revision", &format!("{}", revision))
+        .param("timeout", &format!("{}", timeout.as_secs()))
+        .send()
+        .map(|result| match result {
+            Ok(r) => Ok(Some(r)),
+            Err(e) => {
+                if e.is_timeout() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    pub fn watch_service_by_zone(
+        &self,
+        service: &str,
+        zone: &str,
+        revision: Option<u64>,
+        interval: Duration,
+    ) -> WatchStream<ServiceResult> {
+        let client = self.clone();
+        let (service, zone) = (service.to_string(), zone.to_string());
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            revision,
+            move |revision| match revision {
+                Some(revision) => client
+                    .watch_service_by_zone_once(&service, &zone, revision + 1, interval)
+                    .boxed(),
+                None => client
+                    .get_service_by_zone(&service, &zone)
+                    .map(|result| result.map(Some))
+                    .boxed(),
+            },
+        )
+    }
+
+    pub fn watch_service_zones_once(
+        &self,
+        service: &str,
+        revision: u64,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Option<ServiceWithRawZoneResult>, Error>> {
+        self.long_poll_request(
+            Method::GET,
+            format!("/api/v1/services/{}", service),
+            timeout + self.config.watch_request_overhead,
+        )
+        .param("only_zone", "true")
+        .param("watch", "true")
+        .param("revision", &format!("{}", revision))
+        .param("timeout", &format!("{}", timeout.as_secs()))
+        .send()
+        .map(|result| match result {
+            Ok(r) => Ok(Some(r)),
+            Err(e) => {
+                if e.is_timeout() {
+                    Ok(None)
+                } else {
+                    Err(e)
+                }
+            }
         })
     }
 
+    pub fn watch_service_zones(
+        &self,
+        service: &str,
+        revision: Option<u64>,
+        interval: Duration,
+    ) -> WatchStream<ServiceWithRawZoneResult> {
+        let client = self.clone();
+        let service = service.to_string();
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            revision,
+            move |revision| match revision {
+                Some(revision) => client
+                    .watch_service_zones_once(&service, revision + 1, interval)
+                    .boxed(),
+                None => client
+                    .get_service_only_zones(&service)
+                    .map(|result| result.map(Some))
+                    .boxed(),
+            },
+        )
+    }
+
+    pub fn service_cache(&self, service: &str) -> ServiceCache {
+        ServiceCache::new(self, service)
+    }
+
     pub fn service_keeper(
         &self,
         ttl: Option<i64>,
         app_node: Option<AppNode>,
         endpoint: ServiceEndpoint,
     ) -> ServiceKeeper {
-        ServiceKeeper::new(&self, ttl, app_node, endpoint)
+        ServiceKeeper::new(self, ttl, app_node, endpoint, None)
+    }
+
+    pub fn put_service_desc(&self, desc: &ServiceDesc) -> impl Future<Output = Result<(), Error>> {
+        self.request(Method::POST, "/api/v1/service-descs")
+            .form_result(form!("desc" => desc))
+            .get_ok()
+    }
+
+    pub async fn ensure_service_desc(&self, desc: &ServiceDesc) -> Result<(), Error> {
+        let existing = match self.get_service_by_zone(&desc.service, &desc.zone).await {
+            Ok(result) => result.service.zones.get(&desc.zone).map(|z| z.desc.clone()),
+            Err(e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        match existing {
+            Some(existing) => {
+                if existing.typ != desc.typ || existing.proto != desc.proto {
+                    return Err(Error::Conflict(format!(
+                        "{} exists with type {:?}, proto {:?}",
+                        desc.key(),
+                        existing.typ,
+                        existing.proto
+                    )));
+                }
+                Ok(())
+            }
+            None => self.put_service_desc(desc).await,
+        }
     }
 
     pub fn watch_service_descs_once(
@@ -367,10 +1179,10 @@ impl Client {
         revision: u64,
         timeout: Duration,
     ) -> impl Future<Output = Result<Option<ServiceDescWatchResult>, Error>> {
-        self.request_timeout(
+        self.long_poll_request(
             Method::GET,
             "/api/v1/service-descs",
-            timeout + self.config.request_timeout,
+            timeout + self.config.watch_request_overhead,
         )
         .param_opt("zone", zone)
         .param("revision", &format!("{}", revision))
@@ -388,6 +1200,9 @@ impl Client {
         })
     }
 
+    /// Every yielded result carries the revision it reaches; persist it and pass
+    /// it back as `revision` to resume right after it. With `None` the watch
+    /// replays every event from the first revision.
     pub fn watch_service_descs(
         &self,
         zone: Option<&str>,
@@ -396,96 +1211,344 @@ impl Client {
     ) -> WatchStream<ServiceDescWatchResult> {
         let client = self.clone();
         let zone = zone.map(|s| s.to_string());
-        WatchTask::spawn(revision, move |revision| {
-            client
-                .watch_service_descs_once(zone.as_deref(), revision.unwrap_or(0) + 1, interval)
-                .boxed()
-        })
+        WatchTask::spawn(
+            &self.watches,
+            self.config.new_backoff(),
+            revision,
+            move |revision| {
+                client
+                    .watch_service_descs_once(zone.as_deref(), revision.unwrap_or(0) + 1, interval)
+                    .boxed()
+            },
+        )
+    }
+
+    /// Flattens `watch_service_descs` batches into individual events, in order.
+    /// Watch failures are retried internally, so the stream only ends when closed.
+    pub fn service_desc_event_stream(
+        &self,
+        zone: Option<&str>,
+        revision: Option<u64>,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<ServiceDescEvent, Error>> {
+        self.watch_service_descs(zone, revision, interval)
+            .flat_map(|result| stream::iter(result.events.unwrap_or_default().into_iter().map(Ok)))
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct ItemsResult {
-    configs: Vec<Item>,
-    #[allow(dead_code)]
-    revision: u64,
+/// Revokes its lease on drop, best effort: the revoke is spawned on the current
+/// tokio runtime without waiting for it, and skipped outside of one. The lease
+/// is not renewed, so it also expires after its ttl.
+pub struct LeaseGuard {
+    client: Client,
+    lease_id: Option<i64>,
+}
+
+impl LeaseGuard {
+    pub fn lease_id(&self) -> i64 {
+        self.lease_id.unwrap_or_default()
+    }
+
+    /// Revokes the lease now, waiting for the server.
+    pub async fn revoke(mut self) -> Result<(), Error> {
+        match self.lease_id.take() {
+            Some(lease_id) => self.client.revoke_lease(lease_id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        if let (Some(lease_id), Ok(handle)) = (self.lease_id.take(), Handle::try_current()) {
+            let revoking = self.client.revoke_lease(lease_id);
+            handle.spawn(revoking.map(move |r| {
+                if let Err(e) = r {
+                    if !e.is_not_found() {
+                        error!(target: "xbus::keeper", "revoke lease {:x} fail: {}", lease_id, e);
+                    }
+                }
+            }));
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[non_exhaustive]
 pub struct ItemResult {
     config: Item,
     #[allow(dead_code)]
+    #[serde(default)]
     revision: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Item {
-    pub name: String,
-    pub value: String,
-    pub version: u64,
+struct ConfigPutResult {
+    revision: u64,
 }
 
-impl Item {
-    pub fn json<T>(&self) -> Result<T, serde_json::Error>
-    where
-        for<'de> T: Deserialize<'de>,
-    {
-        serde_json::from_str(&self.value)
-    }
-
-    pub fn yaml<T>(&self) -> Result<T, serde_yaml::Error>
-    where
-        for<'de> T: Deserialize<'de>,
-    {
-        serde_yaml::from_str(&self.value)
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DeleteDryRunResult {
+    dry_run: Option<DeleteServiceSummary>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServiceResult {
-    pub service: Service,
-    pub revision: u64,
+struct ServicesResult {
+    services: HashMap<String, Service>,
+    #[allow(dead_code)]
+    #[serde(default)]
+    revision: u64,
 }
 
-impl RevisionResult for ServiceResult {
-    fn get_revision(&self) -> u64 {
-        self.revision
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+    use crate::service::ZoneService;
+    use serde_json::json;
+
+    fn mock_client() -> (MockTransport, Client) {
+        let transport = MockTransport::new();
+        let client = Client::from_responses(Config::new("http://xbus"), transport.clone()).unwrap();
+        (transport, client)
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct LeaseGrantResult {
-    pub lease_id: i64,
-    pub ttl: i64,
-    pub new_app_node: Option<bool>,
-}
+    fn service(zones: &[(&str, &[&str])]) -> Service {
+        let zones = zones
+            .iter()
+            .map(|(zone, addrs)| {
+                let zone_service = ZoneService {
+                    desc: ServiceDesc::builder("demo.svc", zone).build().unwrap(),
+                    endpoints: addrs
+                        .iter()
+                        .map(|a| ServiceEndpoint::new(a.parse().unwrap(), None))
+                        .collect(),
+                };
+                (zone.to_string(), zone_service)
+            })
+            .collect();
+        Service {
+            service: "demo.svc".to_owned(),
+            zones,
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PlugResult {
-    pub lease_id: i64,
-    pub ttl: i64,
-}
+    #[tokio::test]
+    async fn compare_and_set_returns_written_item() {
+        let (transport, client) = mock_client();
+        transport.respond(
+            Method::PUT,
+            "/api/configs/demo.key",
+            &json!({"revision": 8}),
+        );
+        let item = client.compare_and_set("demo.key", 7, "v2").await.unwrap();
+        assert_eq!(item, Item::new("demo.key", "v2", 8));
+        // 不再回读, 避免读到他人随后写入的值
+        assert_eq!(transport.count(Method::GET, "/api/configs/demo.key"), 0);
+    }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ServiceDescWatchResult {
-    pub events: Option<Vec<ServiceDescEvent>>,
-    pub revision: u64,
-}
+    #[tokio::test]
+    async fn compare_and_set_conflict() {
+        let (transport, client) = mock_client();
+        let path = "/api/configs/demo.key";
+        transport.respond_error(Method::PUT, path, "VERSION_MISMATCH", "version mismatch");
+        let current = Item::new("demo.key", "v3", 9);
+        transport.respond(Method::GET, path, &json!({ "config": current }));
+        match client.compare_and_set("demo.key", 7, "v2").await {
+            Err(Error::VersionConflict { current }) => assert_eq!(current, 9),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 
-impl RevisionResult for ServiceDescWatchResult {
-    fn get_revision(&self) -> u64 {
-        self.revision
+    #[tokio::test]
+    async fn compare_and_set_passes_other_errors() {
+        let (transport, client) = mock_client();
+        let path = "/api/configs/demo.key";
+        transport.respond_error(Method::PUT, path, "INVALID_VALUE", "value too large");
+        match client.compare_and_set("demo.key", 7, "v2").await {
+            Err(e) => assert!(e.to_string().contains("INVALID_VALUE"), "{}", e),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(transport.count(Method::GET, path), 0);
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServiceWithRawZone {
-    pub service: String,
-    pub zones: Vec<String>,
-}
+    #[test]
+    fn app_name_override_survives_rebuild() {
+        let (_, client) = mock_client();
+        let client = client.with_app_name("demo");
+        let rebuilt = client
+            .clone_with_config(|c| c.endpoint = "http://xbus2".to_owned())
+            .unwrap();
+        assert_eq!(rebuilt.get_app_name(), Some("demo"));
+        assert_eq!(rebuilt.config().dev_app, None);
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ServiceWithRawZoneResult {
-    pub service: ServiceWithRawZone,
-    pub revision: u64,
+    #[tokio::test]
+    async fn connect_fails_on_rejected_identity() {
+        let transport = MockTransport::new();
+        let config = Config {
+            dev_app: Some("demo".to_owned()),
+            ..Config::new("http://xbus")
+        };
+        let client = Client::from_responses(config, transport.clone()).unwrap();
+        let path = "/api/apps/demo/nodes";
+        transport.respond_error(Method::GET, path, "NOT_PERMITTED", "unknown app");
+        match client.clone().probe().await {
+            Err(Error::Connect(message)) => assert!(message.contains("demo"), "{}", message),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        transport.respond(Method::GET, path, &json!({"nodes": {}, "revision": 1}));
+        client.probe().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_services_keyed_by_requested_name() {
+        let (transport, client) = mock_client();
+        let mut normalized = service(&[("z1", &["10.0.0.1:80"])]);
+        normalized.service = "demo.svc".to_owned();
+        transport.respond(
+            Method::GET,
+            "/api/v1/services/Demo.Svc",
+            &json!({"service": normalized, "revision": 1}),
+        );
+        let services = client.get_services(&["Demo.Svc"]).await.unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services["Demo.Svc"], normalized);
+    }
+
+    #[tokio::test]
+    async fn get_service_if_changed_sends_quoted_etag() {
+        let (transport, client) = mock_client();
+        let path = "/api/v1/services/demo.svc";
+        transport.respond_raw(
+            Method::GET,
+            path,
+            hyper::StatusCode::NOT_MODIFIED,
+            String::new(),
+        );
+        let result = client.get_service_if_changed("demo.svc", 7).await.unwrap();
+        assert!(result.is_none());
+        assert_eq!(
+            transport.last_header(Method::GET, path, "if-none-match"),
+            Some("\"7\"".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn get_coalesced_not_shared_across_apps() {
+        let (transport, client) = mock_client();
+        let path = "/api/configs/demo.key";
+        transport.respond(
+            Method::GET,
+            path,
+            &json!({"config": Item::new("demo.key", "v", 1)}),
+        );
+        let other = client.clone().with_app_name("other");
+        let (a, b) = future::join(
+            client.get_coalesced("demo.key"),
+            other.get_coalesced("demo.key"),
+        )
+        .await;
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(transport.count(Method::GET, path), 2);
+
+        let (a, b) = future::join(
+            client.get_coalesced("demo.key"),
+            client.clone().get_coalesced("demo.key"),
+        )
+        .await;
+        assert_eq!(a.unwrap(), b.unwrap());
+        assert_eq!(transport.count(Method::GET, path), 3);
+    }
+
+    #[tokio::test]
+    async fn clone_with_config_keeps_custom_connector() {
+        let (transport, client) = mock_client();
+        let path = "/api/configs/demo.key";
+        transport.respond(
+            Method::GET,
+            path,
+            &json!({"config": Item::new("demo.key", "v", 1)}),
+        );
+        let derived = client
+            .clone_with_config(|c| c.endpoint = "http://xbus2".to_owned())
+            .unwrap();
+        derived.get("demo.key").await.unwrap();
+        assert_eq!(transport.count(Method::GET, path), 1);
+    }
+
+    #[tokio::test]
+    async fn clone_with_config_keeps_token_provider_on_rebuild() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let provider: TokenProvider = Arc::new(move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            future::ok("token".to_owned()).boxed()
+        });
+        let client = Client::new(Config::new("http://127.0.0.1:1"))
+            .unwrap()
+            .with_token_provider(provider, Duration::from_secs(60));
+        let rebuilt = client
+            .clone_with_config(|c| c.tcp_nodelay = Some(true))
+            .unwrap();
+        // 连接会失败, 但 token 在发送前获取
+        let _ = rebuilt.get("demo.key").await;
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn clone_with_config_checks_identity() {
+        let config = Config {
+            cert_key_file: Some(("app.pem".to_owned(), "app-key.pem".to_owned())),
+            ..Config::new("http://xbus")
+        };
+        // with_connector 不加载证书, 复用 connector 的路径同样要拒绝 dev_app
+        let client = Client::from_responses(config, MockTransport::new()).unwrap();
+        let err = client
+            .clone_with_config(|c| c.dev_app = Some("demo".to_owned()))
+            .unwrap_err();
+        assert!(err.to_string().contains("dev_app"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn watch_service_snapshots_diff() {
+        let (transport, client) = mock_client();
+        let path = "/api/v1/services/demo.svc";
+        let first = service(&[("z1", &["10.0.0.1:80", "10.0.0.2:80"])]);
+        transport.respond(Method::GET, path, &json!({"service": first, "revision": 1}));
+
+        let mut stream = client.watch_service("demo.svc", None, None, Duration::from_secs(10));
+        let previous = stream.next().await.unwrap();
+        assert_eq!(previous.service, first);
+
+        let second = service(&[("z1", &["10.0.0.2:80"]), ("z2", &["10.0.0.3:80"])]);
+        transport.respond(
+            Method::GET,
+            path,
+            &json!({"service": second, "revision": 2}),
+        );
+        // mock 不区分 revision, 切换响应前的 watch 仍会返回旧快照
+        let current = loop {
+            let result = stream.next().await.unwrap();
+            if result.revision == 2 {
+                break result;
+            }
+        };
+        let diff = current.service.diff(&previous.service);
+        let addr = |a: &str| a.parse::<std::net::SocketAddr>().unwrap();
+        assert_eq!(
+            diff.added,
+            vec![("z2".to_owned(), addr("10.0.0.3:80"))]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            diff.removed,
+            vec![("z1".to_owned(), addr("10.0.0.1:80"))]
+                .into_iter()
+                .collect()
+        );
+    }
 }
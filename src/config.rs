@@ -1,13 +1,23 @@
+use crate::backoff::{Backoff, BackoffFactory};
+use crate::duration_serde;
 use crate::error::Error;
+use crate::request::{DEFAULT_ERROR_BODY_LIMIT, DEFAULT_MAX_RESPONSE_BYTES};
 use rustls::internal::pemfile;
-use rustls::{Certificate, PrivateKey, RootCertStore};
+use rustls::{Certificate, PrivateKey, ProtocolVersion, RootCertStore};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::fs::File;
 use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Once};
 use std::time::Duration;
 
 const DEFAULT_REQUEST_TIMEOUT: u64 = 5;
+const DEFAULT_WATCH_REQUEST_OVERHEAD: u64 = 5;
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     pub endpoint: String,
     pub insecure: bool,
@@ -15,7 +25,176 @@ pub struct Config {
     pub ca_file: Option<String>,
     pub cert_key_file: Option<(String, String)>,
     pub max_idle_connections: Option<usize>,
+    #[serde(
+        default = "default_request_timeout",
+        serialize_with = "duration_serde::serialize",
+        deserialize_with = "deserialize_request_timeout"
+    )]
     pub request_timeout: Duration,
+    /// Added to the long-poll interval of watch requests to form their timeout.
+    #[serde(default = "default_watch_request_overhead", with = "duration_serde")]
+    pub watch_request_overhead: Duration,
+    /// Max bytes of a non-2xx response body kept in the error message.
+    #[serde(default = "default_error_body_limit")]
+    pub error_body_limit: usize,
+    /// Max bytes of a response body, larger ones fail with
+    /// `Error::ResponseTooLarge`; `None` for no limit. Defaults to 64MiB.
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: Option<usize>,
+    /// Gzip request bodies over 1KiB, only for servers accepting
+    /// `Content-Encoding: gzip`.
+    #[serde(default)]
+    pub compress_requests: bool,
+    /// Upper bound of the random delay before the first lease grant, also used
+    /// to spread lease renewals; zero disables jitter.
+    #[serde(default, with = "duration_serde")]
+    pub lease_grant_jitter: Duration,
+    /// Sent as `Authorization: Bearer <token>` on every request.
+    pub bearer_token: Option<String>,
+    /// Whether the server supports fetching several services in one request.
+    #[serde(default)]
+    pub batch_services: bool,
+    /// Fail `Client::new` instead of warning when an https client has no identity.
+    #[serde(default)]
+    pub strict_identity: bool,
+    /// Max concurrent requests, long polls excluded; extra requests are queued.
+    /// Unlimited if unset or 0.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests slower than this are logged with `warn!`; long polls excluded.
+    #[serde(default, with = "duration_serde::option")]
+    pub slow_request_threshold: Option<Duration>,
+    /// Directory where `ServiceCache` persists the last seen topology, one
+    /// `<service>.json` per service, so routing can bootstrap while xbus is down.
+    #[serde(default)]
+    pub topology_snapshot_path: Option<String>,
+    /// Sent as `User-Agent`, `xbus-rs/<version>` if unset.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// Prefix of every request path, for a server mounted under e.g. `/xbus`
+    /// behind a gateway.
+    #[serde(default)]
+    pub base_path: Option<String>,
+    /// Sets `TCP_NODELAY` on connections, hyper's default (off) if unset.
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+    /// Enables TCP keepalive with this idle time, off if unset.
+    #[serde(default, with = "duration_serde::option")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Lowest TLS version offered, `"1.2"` or `"1.3"` in config files; all the
+    /// versions rustls supports if unset.
+    #[serde(
+        default,
+        serialize_with = "serialize_tls_version",
+        deserialize_with = "deserialize_tls_version"
+    )]
+    pub min_tls_version: Option<ProtocolVersion>,
+    /// Hosts connected to at a fixed address instead of resolving them, e.g.
+    /// `{"xbus.example.com": "127.0.0.1:4433"}`. TLS still verifies and sends
+    /// SNI for the original host name.
+    #[serde(default)]
+    pub host_overrides: HashMap<String, SocketAddr>,
+    /// Retry delays of watches, lease grants and replugs, each retry loop using
+    /// its own instance. If unset, watches and replugs retry every 5s and lease
+    /// grants back off exponentially up to 2min.
+    #[serde(skip)]
+    pub backoff: Option<BackoffFactory>,
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Config")
+            .field("endpoint", &self.endpoint)
+            .field("insecure", &self.insecure)
+            .field("dev_app", &self.dev_app)
+            .field("ca_file", &self.ca_file)
+            .field("cert_key_file", &self.cert_key_file)
+            .field("max_idle_connections", &self.max_idle_connections)
+            .field("request_timeout", &self.request_timeout)
+            .field("watch_request_overhead", &self.watch_request_overhead)
+            .field("error_body_limit", &self.error_body_limit)
+            .field("max_response_bytes", &self.max_response_bytes)
+            .field("compress_requests", &self.compress_requests)
+            .field("lease_grant_jitter", &self.lease_grant_jitter)
+            .field(
+                "bearer_token",
+                &self.bearer_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("batch_services", &self.batch_services)
+            .field("strict_identity", &self.strict_identity)
+            .field("max_concurrent_requests", &self.max_concurrent_requests)
+            .field("slow_request_threshold", &self.slow_request_threshold)
+            .field("topology_snapshot_path", &self.topology_snapshot_path)
+            .field("user_agent", &self.user_agent)
+            .field("base_path", &self.base_path)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("min_tls_version", &self.min_tls_version)
+            .field("host_overrides", &self.host_overrides)
+            .field("backoff", &self.backoff.as_ref().map(|_| "<custom>"))
+            .finish()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new("")
+    }
+}
+
+fn default_request_timeout() -> Duration {
+    Duration::from_secs(DEFAULT_REQUEST_TIMEOUT)
+}
+
+fn default_watch_request_overhead() -> Duration {
+    Duration::from_secs(DEFAULT_WATCH_REQUEST_OVERHEAD)
+}
+
+fn default_error_body_limit() -> usize {
+    DEFAULT_ERROR_BODY_LIMIT
+}
+
+fn default_max_response_bytes() -> Option<usize> {
+    Some(DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+fn deserialize_request_timeout<'de, D>(de: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timeout = duration_serde::deserialize(de)?;
+    if timeout == Duration::from_secs(0) {
+        Ok(default_request_timeout())
+    } else {
+        Ok(timeout)
+    }
+}
+
+fn serialize_tls_version<S>(v: &Option<ProtocolVersion>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match v {
+        Some(ProtocolVersion::TLSv1_2) => serializer.serialize_str("1.2"),
+        Some(ProtocolVersion::TLSv1_3) => serializer.serialize_str("1.3"),
+        Some(v) => serializer.serialize_str(&format!("{:?}", v)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_tls_version<'de, D>(de: D) -> Result<Option<ProtocolVersion>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(de)?.as_deref() {
+        None => Ok(None),
+        Some("1.2") => Ok(Some(ProtocolVersion::TLSv1_2)),
+        Some("1.3") => Ok(Some(ProtocolVersion::TLSv1_3)),
+        Some(v) => Err(serde::de::Error::custom(format!(
+            "unsupported tls version: {}, expect 1.2 or 1.3",
+            v
+        ))),
+    }
 }
 
 impl Config {
@@ -27,10 +206,145 @@ impl Config {
             ca_file: None,
             cert_key_file: None,
             max_idle_connections: None,
-            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            request_timeout: default_request_timeout(),
+            watch_request_overhead: default_watch_request_overhead(),
+            error_body_limit: DEFAULT_ERROR_BODY_LIMIT,
+            max_response_bytes: default_max_response_bytes(),
+            compress_requests: false,
+            lease_grant_jitter: Duration::from_secs(0),
+            bearer_token: None,
+            batch_services: false,
+            strict_identity: false,
+            max_concurrent_requests: None,
+            slow_request_threshold: None,
+            topology_snapshot_path: None,
+            user_agent: None,
+            base_path: None,
+            tcp_nodelay: None,
+            tcp_keepalive: None,
+            min_tls_version: None,
+            host_overrides: HashMap::new(),
+            backoff: None,
         }
     }
 
+    pub fn request_timeout(mut self, timeout: Duration) -> Config {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn from_yaml_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let f = File::open(path)?;
+        Ok(serde_yaml::from_reader(io::BufReader::new(f))?)
+    }
+
+    pub fn from_json_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let f = File::open(path)?;
+        Ok(serde_json::from_reader(io::BufReader::new(f))?)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn watch_request_overhead(mut self, overhead: Duration) -> Config {
+        self.watch_request_overhead = overhead;
+        self
+    }
+
+    pub fn lease_grant_jitter(mut self, jitter: Duration) -> Config {
+        self.lease_grant_jitter = jitter;
+        self
+    }
+
+    pub fn max_response_bytes(mut self, max: Option<usize>) -> Config {
+        self.max_response_bytes = max;
+        self
+    }
+
+    pub fn compress_requests(mut self, compress: bool) -> Config {
+        self.compress_requests = compress;
+        self
+    }
+
+    pub fn max_concurrent_requests(mut self, max: usize) -> Config {
+        self.max_concurrent_requests = Some(max);
+        self
+    }
+
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> Config {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    pub fn topology_snapshot_path(mut self, dir: &str) -> Config {
+        self.topology_snapshot_path = Some(dir.to_owned());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Config {
+        self.user_agent = Some(user_agent.to_owned());
+        self
+    }
+
+    pub fn base_path(mut self, path: &str) -> Config {
+        self.base_path = Some(path.to_owned());
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Config {
+        self.tcp_nodelay = Some(nodelay);
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, idle: Duration) -> Config {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    pub fn host_override(mut self, host: impl Into<String>, addr: SocketAddr) -> Config {
+        self.host_overrides.insert(host.into(), addr);
+        self
+    }
+
+    pub fn backoff<F>(mut self, factory: F) -> Config
+    where
+        F: Fn() -> Box<dyn Backoff> + Send + Sync + 'static,
+    {
+        self.backoff = Some(Arc::new(factory));
+        self
+    }
+
+    pub(crate) fn new_backoff(&self) -> Option<Box<dyn Backoff>> {
+        self.backoff.as_ref().map(|factory| factory())
+    }
+
+    pub fn bearer_token(mut self, token: &str) -> Config {
+        self.bearer_token = Some(token.to_owned());
+        self
+    }
+
+    /// Accepts any server certificate. Only meant for development; production
+    /// builds can forbid it by disabling the `dangerous-insecure` feature.
+    pub fn insecure_skip_verify(mut self) -> Config {
+        static WARN: Once = Once::new();
+        WARN.call_once(|| {
+            warn!(
+                target: "xbus::tls",
+                "TLS certificate verification is DISABLED, never use this in production"
+            );
+        });
+        self.insecure = true;
+        self
+    }
+
+    pub fn min_tls_version(mut self, version: ProtocolVersion) -> Config {
+        self.min_tls_version = Some(version);
+        self
+    }
+
     pub fn ca_file(mut self, file: &str) -> Config {
         self.ca_file = Some(file.to_owned());
         self
@@ -94,3 +408,25 @@ fn load_pem_keys(key_path: &str) -> Result<Vec<PrivateKey>, Error> {
         Err(_) => Err(Error::Other(format!("invalid key file: {}", key_path))),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn load_toml_file() {
+        let path = std::env::temp_dir().join(format!("xbus-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "endpoint = \"https://xbus:4433\"\ninsecure = false\ndev_app = \"demo\"\nrequest_timeout = \"3s\"\n",
+        )
+        .unwrap();
+        let config = Config::from_toml_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.endpoint, "https://xbus:4433");
+        assert_eq!(config.dev_app.as_deref(), Some("demo"));
+        assert_eq!(config.request_timeout, Duration::from_secs(3));
+    }
+}
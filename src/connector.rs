@@ -0,0 +1,208 @@
+use futures::future::{poll_fn, BoxFuture};
+use futures::prelude::*;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use std::collections::HashMap;
+use std::io::{Error as IoErr, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub(crate) const UNIX_SCHEME: &str = "unix://";
+// unix socket 上仍按 http 发送请求, host 无实际意义
+pub(crate) const UNIX_HTTP_ENDPOINT: &str = "http://localhost";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Connects to a fixed address for the mapped hosts. Only the uri handed to
+/// the inner connector is rewritten, so the TLS layer above still sees the
+/// original host.
+#[derive(Clone)]
+pub(crate) struct HostOverrides<T> {
+    inner: T,
+    overrides: Arc<HashMap<String, SocketAddr>>,
+}
+
+impl<T> HostOverrides<T> {
+    pub fn new(inner: T, overrides: HashMap<String, SocketAddr>) -> HostOverrides<T> {
+        HostOverrides {
+            inner,
+            overrides: Arc::new(overrides),
+        }
+    }
+}
+
+impl<T: Service<Uri>> Service<Uri> for HostOverrides<T> {
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        match dst.host().and_then(|host| self.overrides.get(host)) {
+            Some(addr) => {
+                let mut parts = dst.into_parts();
+                parts.authority = addr.to_string().parse().ok();
+                let dst = Uri::from_parts(parts).expect("socket address is a valid authority");
+                self.inner.call(dst)
+            }
+            None => self.inner.call(dst),
+        }
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Connection + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Connection + Send + Unpin> Io for T {}
+
+/// Type-erased connection returned by `Connector`.
+pub struct BoxedIo {
+    io: Box<dyn Io>,
+    _guard: ConnGuard,
+}
+
+/// Connections opened by a client; a client only talks to its endpoint,
+/// so these are per host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnStats {
+    /// Total connections opened since the client was created.
+    pub opened: u64,
+    /// Connections currently open, idle ones in the pool included.
+    pub active: usize,
+}
+
+#[derive(Default)]
+struct ConnCounters {
+    opened: AtomicU64,
+    active: AtomicUsize,
+}
+
+struct ConnGuard(Arc<ConnCounters>);
+
+impl ConnGuard {
+    fn new(counters: Arc<ConnCounters>) -> ConnGuard {
+        counters.opened.fetch_add(1, Ordering::Relaxed);
+        counters.active.fetch_add(1, Ordering::Relaxed);
+        ConnGuard(counters)
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for BoxedIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut *self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BoxedIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoErr>> {
+        Pin::new(&mut *self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut *self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut *self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, IoErr>> {
+        Pin::new(&mut *self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+impl Connection for BoxedIo {
+    fn connected(&self) -> Connected {
+        self.io.connected()
+    }
+}
+
+type ConnectFn = dyn Fn(Uri) -> BoxFuture<'static, Result<BoxedIo, BoxError>> + Send + Sync;
+
+/// Type-erased connector used by `Client`, so the transport can be swapped
+/// without changing the client's type.
+#[derive(Clone)]
+pub struct Connector {
+    connect: Arc<ConnectFn>,
+    counters: Arc<ConnCounters>,
+}
+
+impl Connector {
+    pub(crate) fn new<C>(connector: C) -> Connector
+    where
+        C: Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<BoxError>,
+    {
+        let counters = Arc::new(ConnCounters::default());
+        let conn_counters = counters.clone();
+        Connector {
+            connect: Arc::new(move |dst| {
+                let (mut connector, counters) = (connector.clone(), conn_counters.clone());
+                async move {
+                    poll_fn(|cx| connector.poll_ready(cx))
+                        .await
+                        .map_err(Into::into)?;
+                    let io = connector.call(dst).await.map_err(Into::into)?;
+                    Ok(BoxedIo {
+                        io: Box::new(io),
+                        _guard: ConnGuard::new(counters),
+                    })
+                }
+                .boxed()
+            }),
+            counters,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> ConnStats {
+        ConnStats {
+            opened: self.counters.opened.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = BoxedIo;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<BoxedIo, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        (self.connect)(dst)
+    }
+}
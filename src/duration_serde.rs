@@ -0,0 +1,149 @@
+use serde::de::{Deserializer, Error, MapAccess, Unexpected, Visitor};
+use serde::Serializer;
+use std::fmt::{Formatter, Result as FmtResult};
+use std::time::Duration;
+
+pub fn serialize<S>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&humantime::format_duration(*d).to_string())
+}
+
+pub fn deserialize<'de, D>(de: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    de.deserialize_any(DurationVisitor)
+}
+
+struct DurationVisitor;
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "seconds or a duration string like \"5s\"")
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ok(Duration::from_secs(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        if v < 0 {
+            return Err(Error::invalid_value(Unexpected::Signed(v), &self));
+        }
+        Ok(Duration::from_secs(v as u64))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        humantime::parse_duration(v).map_err(|_| Error::invalid_value(Unexpected::Str(v), &self))
+    }
+
+    // 旧版本按 serde 默认格式写出的 {secs, nanos}
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (mut secs, mut nanos) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "secs" => secs = Some(map.next_value::<u64>()?),
+                "nanos" => nanos = Some(map.next_value::<u32>()?),
+                _ => return Err(Error::unknown_field(&key, &["secs", "nanos"])),
+            }
+        }
+        let secs = secs.ok_or_else(|| Error::missing_field("secs"))?;
+        let nanos = nanos.ok_or_else(|| Error::missing_field("nanos"))?;
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "super::deserialize")] Duration);
+
+    pub fn serialize<S>(d: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match d {
+            Some(d) => super::serialize(d, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(de: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Wrapper>::deserialize(de)?.map(|w| w.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Deserialize)]
+    struct Timeout {
+        #[serde(with = "super")]
+        timeout: Duration,
+    }
+
+    fn parse(json: &str) -> Result<Duration, serde_json::Error> {
+        serde_json::from_str::<Timeout>(json).map(|t| t.timeout)
+    }
+
+    #[test]
+    fn integer_seconds() {
+        assert_eq!(parse(r#"{"timeout": 5}"#).unwrap(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn humantime_string() {
+        assert_eq!(
+            parse(r#"{"timeout": "1m 30s"}"#).unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            parse(r#"{"timeout": "250ms"}"#).unwrap(),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn legacy_struct_form() {
+        assert_eq!(
+            parse(r#"{"timeout": {"secs": 3, "nanos": 500000000}}"#).unwrap(),
+            Duration::from_millis(3500)
+        );
+    }
+
+    #[test]
+    fn invalid_input_rejected() {
+        for json in &[
+            r#"{"timeout": -1}"#,
+            r#"{"timeout": "soon"}"#,
+            r#"{"timeout": 1.5}"#,
+            r#"{"timeout": {"secs": 3}}"#,
+            r#"{"timeout": {"secs": 3, "nanos": 0, "millis": 1}}"#,
+        ] {
+            assert!(parse(json).is_err(), "{}", json);
+        }
+    }
+}
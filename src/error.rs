@@ -1,20 +1,35 @@
-use http;
+#[cfg(feature = "client")]
 use hyper::Error as HttpError;
 use serde_json::Error as JsonError;
 use serde_yaml::Error as YamlError;
 use std::error::Error as StdError;
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::io::{Error as IOError, ErrorKind};
+use std::time::Duration;
+#[cfg(feature = "client")]
 use url::ParseError;
 
 #[derive(Debug)]
 pub enum Error {
     Io(IOError),
+    /// Failed to establish the TCP/TLS connection, xbus is unreachable.
+    Connect(String),
     Http(String),
     Ssl(String),
     Serialize(String),
     Request(String, String),
     NotPermitted(String, Vec<String>),
+    Conflict(String),
+    RateLimited {
+        retry_after: Duration,
+    },
+    LeaseExpired(i64),
+    /// The response body exceeded `Config::max_response_bytes`, the limit.
+    ResponseTooLarge(usize),
+    /// A conditional write found the config at another version.
+    VersionConflict {
+        current: u64,
+    },
     Other(String),
 }
 
@@ -40,30 +55,85 @@ impl Error {
         false
     }
 
-    pub fn can_retry(&self) -> bool {
+    /// A plug or replug referred to a lease the server no longer has. Unlike
+    /// `is_not_found`, a missing service or zone does not match.
+    pub fn is_lease_not_found(&self) -> bool {
+        matches!(self, Error::Request(code, _) if code == "LEASE_NOT_FOUND")
+    }
+
+    /// The server rejected a conditional config write made at a stale version.
+    pub fn is_version_mismatch(&self) -> bool {
+        matches!(self, Error::Request(code, _) if code == "VERSION_MISMATCH")
+    }
+
+    pub fn not_permitted_keys(&self) -> Option<&[String]> {
         match self {
-            Error::Ssl(_) => false,
-            Error::Serialize(_) => false,
-            Error::Request(code, _) => match code.as_str() {
-                "SYSTEM_ERROR" | "TOO_MANY_ATTEMPTS" | "DEADLINE_EXCEEDED" | "CANCELLED" => true,
-                _ => false,
-            },
-            Error::NotPermitted(_, _) => false,
-            Error::Other(_) => false,
-            _ => true,
+            Error::NotPermitted(_, keys) => Some(keys),
+            _ => None,
+        }
+    }
+
+    /// Delay requested by the server through `Retry-After`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    pub fn is_connect(&self) -> bool {
+        matches!(self, Error::Connect(_))
+    }
+
+    /// Transport failures (connection, timeout, http) that are worth retrying.
+    pub fn is_retryable_network(&self) -> bool {
+        matches!(self, Error::Io(_) | Error::Connect(_) | Error::Http(_))
+    }
+
+    /// Failures the server signals as retryable, by error code or rate limiting.
+    pub fn is_retryable_code(&self) -> bool {
+        match self {
+            Error::Request(code, _) => matches!(
+                code.as_str(),
+                "SYSTEM_ERROR" | "TOO_MANY_ATTEMPTS" | "DEADLINE_EXCEEDED" | "CANCELLED"
+            ),
+            Error::RateLimited { .. } => true,
+            _ => false,
         }
     }
+
+    pub fn can_retry(&self) -> bool {
+        self.is_retryable_network() || self.is_retryable_code()
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
         match self {
             Error::Io(e) => e.fmt(f),
+            Error::Connect(e) => write!(f, "connect fail: {}", e),
             Error::Http(e) => write!(f, "{}", e),
             Error::Ssl(e) => write!(f, "{}", e),
             Error::Serialize(e) => write!(f, "{}", e),
             Error::Request(code, message) => write!(f, "request fail[{}]: {}", code, message),
-            Error::NotPermitted(message, _) => write!(f, "not permitted: {}", message),
+            Error::NotPermitted(message, keys) => {
+                if keys.is_empty() {
+                    write!(f, "not permitted: {}", message)
+                } else {
+                    write!(f, "not permitted: {} [{}]", message, keys.join(", "))
+                }
+            }
+            Error::Conflict(e) => write!(f, "conflict: {}", e),
+            Error::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {:?}", retry_after)
+            }
+            Error::LeaseExpired(lease_id) => write!(f, "lease expired: {:x}", lease_id),
+            Error::ResponseTooLarge(limit) => {
+                write!(f, "response body larger than {} bytes", limit)
+            }
+            Error::VersionConflict { current } => {
+                write!(f, "version conflict, current version: {}", current)
+            }
             Error::Other(e) => write!(f, "{}", e),
         }
     }
@@ -71,14 +141,46 @@ impl Display for Error {
 
 impl StdError for Error {}
 
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            Error::Io(e) => Error::Io(IOError::new(e.kind(), e.to_string())),
+            Error::Connect(e) => Error::Connect(e.clone()),
+            Error::Http(e) => Error::Http(e.clone()),
+            Error::Ssl(e) => Error::Ssl(e.clone()),
+            Error::Serialize(e) => Error::Serialize(e.clone()),
+            Error::Request(code, message) => Error::Request(code.clone(), message.clone()),
+            Error::NotPermitted(message, keys) => {
+                Error::NotPermitted(message.clone(), keys.clone())
+            }
+            Error::Conflict(e) => Error::Conflict(e.clone()),
+            Error::RateLimited { retry_after } => Error::RateLimited {
+                retry_after: *retry_after,
+            },
+            Error::LeaseExpired(lease_id) => Error::LeaseExpired(*lease_id),
+            Error::ResponseTooLarge(limit) => Error::ResponseTooLarge(*limit),
+            Error::VersionConflict { current } => Error::VersionConflict { current: *current },
+            Error::Other(e) => Error::Other(e.clone()),
+        }
+    }
+}
+
 impl From<IOError> for Error {
     fn from(err: IOError) -> Error {
         Error::Io(err)
     }
 }
 
+#[cfg(feature = "client")]
 impl From<HttpError> for Error {
     fn from(err: HttpError) -> Error {
+        if err.is_connect() {
+            // hyper 只给出 "error trying to connect", 具体原因在 source 里
+            return match err.source() {
+                Some(source) => Error::Connect(source.to_string()),
+                None => Error::Connect(err.to_string()),
+            };
+        }
         Error::Http(format!("{}", err))
     }
 }
@@ -95,6 +197,14 @@ impl From<YamlError> for Error {
     }
 }
 
+#[cfg(feature = "toml")]
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Serialize(format!("{}", err))
+    }
+}
+
+#[cfg(feature = "client")]
 impl From<ParseError> for Error {
     fn from(err: ParseError) -> Error {
         Error::Other(format!("parse url fail: {}", err))
@@ -113,6 +223,7 @@ impl<'a> From<&'a str> for Error {
     }
 }
 
+#[cfg(feature = "client")]
 impl From<http::Error> for Error {
     fn from(e: http::Error) -> Error {
         Error::Http(format!("{}", e))
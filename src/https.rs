@@ -4,16 +4,16 @@ use futures::prelude::*;
 use hyper::client::connect::{Connected, Connection};
 use hyper::service::Service;
 use hyper::Uri;
-use tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey};
 use std::io::{Error as IoErr, IoSlice};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_rustls::client::TlsStream;
-use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey};
 use tokio_rustls::webpki;
 use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
 
 pub trait TlsClientConfigExt {
     fn set_insecure(&mut self);
@@ -43,10 +43,16 @@ pub struct HttpsConnector<T> {
 }
 
 impl<T> HttpsConnector<T> {
-    pub fn new(mut config: ClientConfig, http: T) -> HttpsConnector<T> {
-        config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    pub fn new(config: ClientConfig, http: T) -> HttpsConnector<T> {
+        // 关闭 webpki-roots feature 时只信任配置的 CA
+        #[cfg(feature = "webpki-roots")]
+        let config = {
+            let mut config = config;
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            config
+        };
         HttpsConnector {
             http,
             tls: Arc::new(config).into(),
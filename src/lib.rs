@@ -1,31 +1,95 @@
+#[cfg(feature = "client")]
 extern crate futures;
+#[cfg(feature = "client")]
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde;
 
+#[cfg(feature = "client")]
+mod auth;
+#[cfg(feature = "client")]
+mod backoff;
+#[cfg(feature = "client")]
 pub mod cert;
 pub mod error;
+#[cfg(feature = "client")]
 mod https;
+// crate 内的单元测试也用 MockTransport
+#[cfg(any(feature = "test-util", all(test, feature = "client")))]
+mod mock;
+#[cfg(feature = "client")]
 #[macro_use]
 mod request;
 mod addr_serde;
+#[cfg(feature = "client")]
 pub mod client;
+/// Wire types only, the `client` feature is disabled.
+#[cfg(not(feature = "client"))]
+pub mod client {
+    pub use crate::results::*;
+}
+#[cfg(feature = "client")]
 mod config;
+#[cfg(feature = "client")]
+mod connector;
+#[cfg(feature = "client")]
+mod duration_serde;
+mod results;
 pub mod service;
+#[cfg(feature = "client")]
+mod service_cache;
+#[cfg(feature = "client")]
 mod service_keeper;
+#[cfg(feature = "client")]
+mod single_flight;
+#[cfg(all(unix, feature = "unix-socket"))]
+mod unix;
+#[cfg(feature = "client")]
 mod watcher;
 
-pub use self::client::Client;
+#[cfg(feature = "client")]
+pub use self::auth::TokenProvider;
+#[cfg(feature = "client")]
+pub use self::backoff::{Backoff, BackoffFactory, ConstantBackoff, ExponentialBackoff};
+#[cfg(feature = "client")]
+pub use self::client::{Client, LeaseGuard};
+#[cfg(feature = "client")]
 pub use self::config::Config;
+#[cfg(feature = "client")]
+pub use self::connector::{BoxedIo, ConnStats, Connector};
 pub use self::error::Error;
-pub use self::https::TlsClientConfigExt;
+#[cfg(feature = "client")]
+pub use self::https::{HttpsConnector, TlsClientConfigExt};
+#[cfg(feature = "test-util")]
+pub use self::mock::MockTransport;
+#[cfg(feature = "client")]
 pub use self::request::Form;
-pub use self::service_keeper::ServiceKeeper;
+#[cfg(feature = "client")]
+pub use self::service_cache::ServiceCache;
+#[cfg(feature = "client")]
+pub use self::service_keeper::{KeeperEvent, PlugGuard, RetryPolicy, ServiceKeeper};
+#[cfg(feature = "client")]
 pub use self::watcher::WatchHandle;
 
 pub const DEFAULT_ZONE: &str = "default";
 
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
 pub(crate) trait RevisionResult {
     fn get_revision(&self) -> u64;
 }
+
+impl<T: RevisionResult> RevisionResult for std::sync::Arc<T> {
+    fn get_revision(&self) -> u64 {
+        (**self).get_revision()
+    }
+}
+
+pub(crate) fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
@@ -0,0 +1,188 @@
+use crate::client::Client;
+use crate::config::Config;
+use crate::error::Error;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use hyper::client::connect::{Connected, Connection};
+use hyper::header::{HeaderMap, RETRY_AFTER};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Error as IoErr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+const MOCK_BUFFER_SIZE: usize = 64 * 1024;
+
+type Responses = HashMap<(Method, String), (StatusCode, Option<u64>, String)>;
+type Requests = Vec<(Method, String, HeaderMap)>;
+
+/// In-memory transport answering requests with canned responses keyed by
+/// method and path (query string ignored), for testing code built on `Client`.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    responses: Arc<Mutex<Responses>>,
+    requests: Arc<Mutex<Requests>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Responds with `result` wrapped in a successful xbus envelope.
+    pub fn respond<T: Serialize>(&self, method: Method, path: &str, result: &T) {
+        let body = serde_json::json!({"ok": true, "result": result}).to_string();
+        self.respond_raw(method, path, StatusCode::OK, body);
+    }
+
+    /// Responds with an xbus error envelope, e.g. code `NOT_FOUND`.
+    pub fn respond_error(&self, method: Method, path: &str, code: &str, message: &str) {
+        let body = serde_json::json!({"ok": false, "error": {"code": code, "message": message}})
+            .to_string();
+        self.respond_raw(method, path, StatusCode::OK, body);
+    }
+
+    pub fn respond_raw(&self, method: Method, path: &str, status: StatusCode, body: String) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert((method, path.to_owned()), (status, None, body));
+    }
+
+    /// Responds with `429 Too Many Requests` and a `Retry-After` in whole seconds.
+    pub fn respond_rate_limited(&self, method: Method, path: &str, retry_after: Duration) {
+        self.responses.lock().unwrap().insert(
+            (method, path.to_owned()),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(retry_after.as_secs()),
+                String::new(),
+            ),
+        );
+    }
+
+    /// Method and path of every request received so far, in order.
+    pub fn requests(&self) -> Vec<(Method, String)> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(m, p, _)| (m.clone(), p.clone()))
+            .collect()
+    }
+
+    /// Header `name` of the last request for `method` and `path`.
+    pub fn last_header(&self, method: Method, path: &str, name: &str) -> Option<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(m, p, _)| *m == method && p == path)
+            .and_then(|(_, _, headers)| headers.get(name)?.to_str().ok().map(String::from))
+    }
+
+    /// How many requests were received for `method` and `path`.
+    pub fn count(&self, method: Method, path: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, p, _)| *m == method && p == path)
+            .count()
+    }
+
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let key = (req.method().clone(), req.uri().path().to_owned());
+        self.requests
+            .lock()
+            .unwrap()
+            .push((key.0.clone(), key.1.clone(), req.headers().clone()));
+        let (status, retry_after, body) = match self.responses.lock().unwrap().get(&key) {
+            Some(resp) => resp.clone(),
+            None => (
+                StatusCode::OK,
+                None,
+                serde_json::json!({"ok": false, "error": {
+                    "code": "NOT_FOUND",
+                    "message": format!("no mock response for {} {}", key.0, key.1),
+                }})
+                .to_string(),
+            ),
+        };
+        let mut resp = Response::new(Body::from(body));
+        *resp.status_mut() = status;
+        if let Some(secs) = retry_after {
+            resp.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        resp
+    }
+}
+
+impl Service<Uri> for MockTransport {
+    type Response = MockStream;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<MockStream, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _dst: Uri) -> Self::Future {
+        let (client_io, server_io) = duplex(MOCK_BUFFER_SIZE);
+        let transport = self.clone();
+        let service = service_fn(move |req| future::ok::<_, Infallible>(transport.handle(req)));
+        tokio::spawn(Http::new().serve_connection(server_io, service).map(|_| ()));
+        future::ok(MockStream(client_io)).boxed()
+    }
+}
+
+pub struct MockStream(DuplexStream);
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoErr>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoErr>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl Connection for MockStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl Client {
+    /// Builds a client whose requests are answered by `transport` without network I/O.
+    pub fn from_responses(config: Config, transport: MockTransport) -> Result<Client, Error> {
+        Client::with_connector(config, transport)
+    }
+}
@@ -1,28 +1,85 @@
+use crate::auth::TokenSource;
 use crate::error::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures::prelude::*;
+use std::io::Write;
 use std::pin::Pin;
 
+use http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER,
+};
 use http::request::Builder;
-use http::{Method, Uri};
+use http::{Method, StatusCode, Uri};
+use hyper::body::{Bytes, HttpBody};
 use hyper::client::connect::Connect;
 use hyper::client::Client;
 use hyper::Body;
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::{from_slice, to_string};
+use serde_json::to_string;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
 use tokio::time::timeout;
 use url::form_urlencoded;
 
+pub const DEFAULT_ERROR_BODY_LIMIT: usize = 2048;
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 64 << 20;
+// 小 body 压缩收益不大, 只压缩超过此大小的
+const COMPRESS_MIN_BYTES: usize = 1024;
+
+type ResponseFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send>>;
+
+/// Caps concurrent requests and counts those in flight.
+#[derive(Clone)]
+pub(crate) struct RequestLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl RequestLimiter {
+    // 0 视为不限制, 否则 Semaphore::new(0) 会让所有请求永远等待
+    pub fn new(max_concurrent: Option<usize>) -> RequestLimiter {
+        RequestLimiter {
+            semaphore: max_concurrent
+                .filter(|n| *n > 0)
+                .map(|n| Arc::new(Semaphore::new(n))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 pub struct RequestBuilder<'a, C: 'static + Connect> {
     client: &'a Client<C>,
     endpoint: &'a str,
-    path: &'a str,
-    params: HashMap<&'a str, &'a str>,
+    path: Cow<'a, str>,
+    params: HashMap<Cow<'a, str>, Cow<'a, str>>,
     body: Option<Body>,
     builder: Builder,
     timeout: Option<Duration>,
+    error_body_limit: usize,
+    max_response_bytes: Option<usize>,
+    compress: bool,
+    token_source: Option<TokenSource>,
+    limiter: Option<RequestLimiter>,
+    slow_threshold: Option<Duration>,
     pending_err: Option<Error>,
 }
 
@@ -31,7 +88,7 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
         client: &'a Client<C>,
         endpoint: &'a str,
         method: Method,
-        path: &'a str,
+        path: impl Into<Cow<'a, str>>,
         timeout: Option<Duration>,
     ) -> RequestBuilder<'a, C> {
         let mut builder = Builder::new();
@@ -39,52 +96,151 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
         RequestBuilder {
             client,
             endpoint,
-            path,
+            path: path.into(),
             params: HashMap::new(),
             body: None,
             builder,
             timeout,
+            error_body_limit: DEFAULT_ERROR_BODY_LIMIT,
+            max_response_bytes: None,
+            compress: false,
+            token_source: None,
+            limiter: None,
+            slow_threshold: None,
             pending_err: None,
         }
     }
 
+    pub fn error_body_limit(mut self, limit: usize) -> RequestBuilder<'a, C> {
+        self.error_body_limit = limit;
+        self
+    }
+
+    pub fn max_response_bytes(mut self, max: Option<usize>) -> RequestBuilder<'a, C> {
+        self.max_response_bytes = max;
+        self
+    }
+
     pub fn param(mut self, name: &'a str, value: &'a str) -> RequestBuilder<'a, C> {
-        self.params.insert(name, value);
+        self.params.insert(name.into(), value.into());
         self
     }
 
     pub fn param_opt(mut self, name: &'a str, value: Option<&'a str>) -> RequestBuilder<'a, C> {
         if let Some(val) = value {
-            self.params.insert(name, val);
+            self.params.insert(name.into(), val.into());
         }
         self
     }
 
-    pub fn header(mut self, name: &'a str, value: &'a str) -> RequestBuilder<'a, C> {
+    // 按 serde_urlencoded 编码, None 字段忽略, 嵌套结构与序列报错
+    pub fn query_struct<T: Serialize>(mut self, t: &T) -> RequestBuilder<'a, C> {
+        let encoded = match serde_urlencoded::to_string(t) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                return self
+                    .with_pending_err(Error::Serialize(format!("serialize query fail: {}", e)));
+            }
+        };
+        for (k, v) in form_urlencoded::parse(encoded.as_bytes()) {
+            self.params
+                .insert(k.into_owned().into(), v.into_owned().into());
+        }
+        self
+    }
+
+    fn with_pending_err(mut self, e: Error) -> RequestBuilder<'a, C> {
+        if self.pending_err.is_none() {
+            self.pending_err = Some(e);
+        }
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> RequestBuilder<'a, C> {
         self.builder = self.builder.header(name, value);
         self
     }
 
+    pub(crate) fn header_value(
+        mut self,
+        name: HeaderName,
+        value: HeaderValue,
+    ) -> RequestBuilder<'a, C> {
+        self.builder = self.builder.header(name, value);
+        self
+    }
+
+    /// Gzips form and json bodies larger than 1KiB, set before attaching the body.
+    pub fn compress(mut self, compress: bool) -> RequestBuilder<'a, C> {
+        self.compress = compress;
+        self
+    }
+
+    pub(crate) fn limiter(mut self, limiter: Option<RequestLimiter>) -> RequestBuilder<'a, C> {
+        self.limiter = limiter;
+        self
+    }
+
+    pub(crate) fn slow_threshold(mut self, threshold: Option<Duration>) -> RequestBuilder<'a, C> {
+        self.slow_threshold = threshold;
+        self
+    }
+
+    pub(crate) fn token_source(mut self, source: TokenSource) -> RequestBuilder<'a, C> {
+        self.token_source = Some(source);
+        self
+    }
+
+    pub fn bearer_auth(mut self, token: &str) -> RequestBuilder<'a, C> {
+        match HeaderValue::from_str(&format!("Bearer {}", token)) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                self.builder = self.builder.header(AUTHORIZATION, value);
+                self
+            }
+            Err(_) => self.with_pending_err(Error::Other("invalid bearer token".to_owned())),
+        }
+    }
+
     pub fn body<B: Into<Body>>(mut self, body: B) -> RequestBuilder<'a, C> {
         self.body = Some(body.into());
         self
     }
 
-    pub fn form(mut self, form: Form) -> RequestBuilder<'a, C> {
-        self.builder = self
-            .builder
-            .header("Content-Type", "application/x-www-form-urlencoded");
-        self.body(form)
+    pub fn form(self, form: Form) -> RequestBuilder<'a, C> {
+        self.encoded_body("application/x-www-form-urlencoded", form.encode())
+    }
+
+    fn encoded_body(mut self, content_type: &str, body: String) -> RequestBuilder<'a, C> {
+        self.builder = self.builder.header(CONTENT_TYPE, content_type);
+        if !self.compress || body.len() < COMPRESS_MIN_BYTES {
+            return self.body(body);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        match encoder
+            .write_all(body.as_bytes())
+            .and_then(|_| encoder.finish())
+        {
+            Ok(gzipped) => {
+                self.builder = self.builder.header(CONTENT_ENCODING, "gzip");
+                self.body(gzipped)
+            }
+            Err(e) => self.with_pending_err(Error::Other(format!("gzip body fail: {}", e))),
+        }
     }
 
-    pub fn form_result(mut self, form: Result<Form, Error>) -> RequestBuilder<'a, C> {
+    pub fn form_result(self, form: Result<Form, Error>) -> RequestBuilder<'a, C> {
         match form {
             Ok(form) => self.form(form),
+            Err(e) => self.with_pending_err(e),
+        }
+    }
+
+    pub fn json<T: Serialize>(self, value: &T) -> RequestBuilder<'a, C> {
+        match to_string(value) {
+            Ok(body) => self.encoded_body("application/json", body),
             Err(e) => {
-                if self.pending_err.is_none() {
-                    self.pending_err = Some(e);
-                }
-                self
+                self.with_pending_err(Error::Serialize(format!("serialize json body fail: {}", e)))
             }
         }
     }
@@ -103,7 +259,16 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
         self.form(form)
     }
 
-    fn get_response<T>(mut self) -> Pin<Box<dyn Future<Output = Result<Response<T>, Error>> + Send>>
+    fn get_response<T>(self) -> ResponseFuture<Response<T>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'static,
+    {
+        self.get_response_with_headers()
+            .map_ok(|(resp, _)| resp)
+            .boxed()
+    }
+
+    fn get_response_with_headers<T>(mut self) -> ResponseFuture<(Response<T>, HeaderMap)>
     where
         for<'de> T: Deserialize<'de> + Send + 'static,
     {
@@ -112,7 +277,7 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
         }
 
         let mut url_str = self.endpoint.to_owned();
-        url_str.push_str(self.path);
+        url_str.push_str(&self.path);
         if !self.params.is_empty() {
             url_str.push('?');
             url_str.push_str(
@@ -139,25 +304,85 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
                 return future::err(Error::from(e)).boxed();
             }
         };
-        trace!("request xbus: {} {}", request.method(), request.uri());
-        let resp_fut = self
-            .client
-            .request(request)
-            .map_err(Error::from)
-            .and_then(|resp| {
-                let status = resp.status();
-                hyper::body::to_bytes(resp).map(move |result| match result {
-                    Ok(body) => {
-                        if !status.is_success() {
-                            let msg = format!("[{}]: {}", status, String::from_utf8_lossy(&body));
-                            return Err(Error::from(msg));
+        trace!(target: "xbus::request", "request xbus: {} {}", request.method(), request.uri());
+        let (error_body_limit, max_response_bytes) =
+            (self.error_body_limit, self.max_response_bytes);
+        let (client, token_source, limiter, slow_threshold) = (
+            self.client.clone(),
+            self.token_source,
+            self.limiter,
+            self.slow_threshold,
+        );
+        let (method, path) = (request.method().clone(), request.uri().path().to_owned());
+        let resp_fut = async move {
+            let mut request = request;
+            let mut _permit = None;
+            let mut _in_flight = None;
+            if let Some(limiter) = limiter {
+                if let Some(semaphore) = limiter.semaphore {
+                    _permit = Some(
+                        semaphore
+                            .acquire_owned()
+                            .await
+                            .map_err(|_| Error::Other("request limiter closed".to_owned()))?,
+                    );
+                }
+                limiter.in_flight.fetch_add(1, Ordering::Relaxed);
+                _in_flight = Some(InFlightGuard(limiter.in_flight));
+            }
+            if let Some(source) = token_source {
+                let mut value = HeaderValue::from_str(&format!("Bearer {}", source.token().await?))
+                    .map_err(|_| Error::Other("invalid bearer token".to_owned()))?;
+                value.set_sensitive(true);
+                request.headers_mut().insert(AUTHORIZATION, value);
+            }
+            let start = Instant::now();
+            let resp = client.request(request).await.map_err(Error::from)?;
+            Ok((resp, (_permit, _in_flight), start))
+        }
+        .and_then(move |(resp, limit_guard, start)| {
+            let status = resp.status();
+            let (parts, body) = resp.into_parts();
+            let headers = parts.headers;
+            // 读完 body 才释放并发名额
+            read_body(body, max_response_bytes).map(move |result| match result {
+                Ok(body) => {
+                    drop(limit_guard);
+                    if let Some(threshold) = slow_threshold {
+                        let elapsed = start.elapsed();
+                        if elapsed > threshold {
+                            warn!(
+                                target: "xbus::request",
+                                "slow request: {} {} took {:?}", method, path, elapsed
+                            );
                         }
-                        let json_rep: Response<T> = from_slice(&body)?;
-                        Ok(json_rep)
                     }
-                    Err(e) => Err(Error::from(e)),
-                })
-            });
+                    if status == StatusCode::NOT_MODIFIED {
+                        let json_rep = Response {
+                            ok: true,
+                            result: None,
+                            error: None,
+                        };
+                        return Ok((json_rep, headers));
+                    }
+                    if status == StatusCode::TOO_MANY_REQUESTS
+                        || status == StatusCode::SERVICE_UNAVAILABLE
+                    {
+                        if let Some(retry_after) = parse_retry_after(&headers) {
+                            return Err(Error::RateLimited { retry_after });
+                        }
+                    }
+                    if !status.is_success() {
+                        let msg =
+                            format!("[{}]: {}", status, truncate_body(&body, error_body_limit));
+                        return Err(Error::from(msg));
+                    }
+                    let json_rep: Response<T> = decode_response(&body)?;
+                    Ok((json_rep, headers))
+                }
+                Err(e) => Err(e),
+            })
+        });
         if let Some(to) = self.timeout {
             return timeout(to, resp_fut)
                 .map(|result| match result {
@@ -177,6 +402,15 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
             .map(|result| result.and_then(|resp| resp.get()))
     }
 
+    /// Like `send`, also returning the response headers (e.g. `X-Request-Id`).
+    pub fn send_with_headers<T>(self) -> impl Future<Output = Result<(T, HeaderMap), Error>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'static,
+    {
+        self.get_response_with_headers()
+            .map(|result| result.and_then(|(resp, headers)| Ok((resp.get()?, headers))))
+    }
+
     pub fn get_option<T>(self) -> impl Future<Output = Result<Option<T>, Error>>
     where
         for<'de> T: Deserialize<'de> + Send + 'static,
@@ -191,6 +425,80 @@ impl<'a, C: Connect + Send + Sync + Clone + 'static> RequestBuilder<'a, C> {
     }
 }
 
+async fn read_body(mut body: Body, limit: Option<usize>) -> Result<Bytes, Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(hyper::body::to_bytes(body).await?),
+    };
+    if body.size_hint().lower() as usize > limit {
+        return Err(Error::ResponseTooLarge(limit));
+    }
+    // 边读边计数, 超出上限立即放弃, 不等整个 body 读完
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Err(Error::ResponseTooLarge(limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
+// Retry-After 可以是秒数或 HTTP-date
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_secs(0)),
+    )
+}
+
+fn truncate_body(body: &[u8], limit: usize) -> Cow<'_, str> {
+    if body.len() <= limit {
+        return String::from_utf8_lossy(body);
+    }
+    let mut msg = String::from_utf8_lossy(&body[..limit]).into_owned();
+    msg.push_str("…(truncated)");
+    Cow::Owned(msg)
+}
+
+const DECODE_EXCERPT_RADIUS: usize = 40;
+
+fn decode_response<T: DeserializeOwned>(body: &[u8]) -> Result<Response<T>, Error> {
+    let mut de = serde_json::Deserializer::from_slice(body);
+    let resp = serde_path_to_error::deserialize(&mut de)
+        .map_err(|e| decode_error::<T>(body, &e.path().to_string(), e.inner()))?;
+    de.end().map_err(|e| decode_error::<T>(body, ".", &e))?;
+    Ok(resp)
+}
+
+fn decode_error<T>(body: &[u8], path: &str, e: &serde_json::Error) -> Error {
+    // serde_json 的 line/column 从 1 开始, column 为 0 表示出错位置未知
+    let mut offset = 0;
+    for (i, line) in body.split(|b| *b == b'\n').enumerate() {
+        if i + 1 == e.line() {
+            offset += e.column().saturating_sub(1).min(line.len());
+            break;
+        }
+        offset += line.len() + 1;
+    }
+    let offset = offset.min(body.len());
+    let start = offset.saturating_sub(DECODE_EXCERPT_RADIUS);
+    let end = (offset + DECODE_EXCERPT_RADIUS).min(body.len());
+    Error::Serialize(format!(
+        "decode {} fail at `{}`: {}, near `{}`",
+        std::any::type_name::<T>(),
+        path,
+        e,
+        String::from_utf8_lossy(&body[start..end])
+    ))
+}
+
 #[derive(Deserialize, Debug)]
 struct RespError {
     pub code: String,
@@ -250,15 +558,14 @@ impl<T> Response<T> {
     }
 }
 
-pub struct Form<'a> {
-    serializer: form_urlencoded::Serializer<'a, String>,
+#[derive(Default)]
+pub struct Form {
+    pairs: Vec<(String, String)>,
 }
 
-impl<'a> Form<'a> {
-    pub fn new() -> Form<'a> {
-        Form {
-            serializer: form_urlencoded::Serializer::new(String::new()),
-        }
+impl Form {
+    pub fn new() -> Form {
+        Form { pairs: Vec::new() }
     }
 
     pub fn set<T: Serialize>(&mut self, name: &str, v: T) -> Result<(), Error> {
@@ -274,14 +581,33 @@ impl<'a> Form<'a> {
         if data == "null" {
             data = String::new();
         }
-        self.serializer.append_pair(name, &data);
+        match self.pairs.iter_mut().find(|(k, _)| k == name) {
+            Some(pair) => pair.1 = data,
+            None => self.pairs.push((name.to_owned(), data)),
+        }
         Ok(())
     }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|(k, _)| k == name)
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.pairs.retain(|(k, _)| k != name);
+    }
+}
+
+impl Form {
+    fn encode(self) -> String {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        serializer.extend_pairs(self.pairs);
+        serializer.finish()
+    }
 }
 
-impl<'a> Into<Body> for Form<'a> {
-    fn into(mut self) -> Body {
-        Body::from(self.serializer.finish())
+impl From<Form> for Body {
+    fn from(form: Form) -> Body {
+        Body::from(form.encode())
     }
 }
 
@@ -293,3 +619,96 @@ macro_rules! form {
         result.and(Ok(form))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Query {
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    }
+
+    #[derive(Serialize)]
+    struct SeqQuery {
+        keys: Vec<String>,
+    }
+
+    #[test]
+    fn query_struct_skips_none_fields() {
+        let client = Client::new();
+        let query = Query {
+            prefix: "a b&c".to_owned(),
+            after: None,
+            limit: 10,
+        };
+        let builder = RequestBuilder::new(&client, "http://xbus", Method::GET, "/", None)
+            .query_struct(&query);
+        assert!(builder.pending_err.is_none());
+        assert_eq!(builder.params.len(), 2);
+        assert_eq!(builder.params["prefix"], "a b&c");
+        assert_eq!(builder.params["limit"], "10");
+
+        let query = Query {
+            after: Some("k1".to_owned()),
+            ..query
+        };
+        let builder = RequestBuilder::new(&client, "http://xbus", Method::GET, "/", None)
+            .query_struct(&query);
+        assert_eq!(builder.params["after"], "k1");
+    }
+
+    #[test]
+    fn query_struct_rejects_sequences() {
+        let client = Client::new();
+        let query = SeqQuery {
+            keys: vec!["a".to_owned(), "b".to_owned()],
+        };
+        let builder = RequestBuilder::new(&client, "http://xbus", Method::GET, "/", None)
+            .query_struct(&query);
+        assert!(builder.params.is_empty());
+        assert!(matches!(builder.pending_err, Some(Error::Serialize(_))));
+    }
+
+    #[test]
+    fn zero_max_concurrent_is_unlimited() {
+        assert!(RequestLimiter::new(Some(0)).semaphore.is_none());
+        assert!(RequestLimiter::new(Some(2)).semaphore.is_some());
+    }
+
+    #[test]
+    fn error_body_within_limit_kept() {
+        assert_eq!(truncate_body(b"not found", 9), "not found");
+    }
+
+    #[test]
+    fn oversized_error_body_truncated() {
+        let body = vec![b'x'; DEFAULT_ERROR_BODY_LIMIT * 4];
+        let msg = truncate_body(&body, DEFAULT_ERROR_BODY_LIMIT);
+        assert!(msg.ends_with("…(truncated)"));
+        assert_eq!(msg.len(), DEFAULT_ERROR_BODY_LIMIT + "…(truncated)".len());
+    }
+
+    #[test]
+    fn decode_error_reports_field_path() {
+        let body = br#"{"ok": true, "result": {"configs": [
+            {"name": "a", "value": "1", "version": 1},
+            {"name": "b", "value": "2", "version": "two"}
+        ]}}"#;
+        let err = decode_response::<crate::results::ItemsResult>(body)
+            .err()
+            .expect("version is not a number");
+        let msg = err.to_string();
+        assert!(msg.contains("`result.configs[1].version`"), "{}", msg);
+        assert!(msg.contains("\"two\""), "{}", msg);
+    }
+
+    #[test]
+    fn truncation_splitting_utf8_char() {
+        // "错" 占 3 字节, 截断在中间时替换为 U+FFFD 而不是 panic
+        let msg = truncate_body("错误".as_bytes(), 2);
+        assert_eq!(msg, "\u{fffd}…(truncated)");
+    }
+}
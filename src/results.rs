@@ -0,0 +1,147 @@
+use crate::service::{Service, ServiceDescEvent, ServiceEndpoint};
+use crate::RevisionResult;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[non_exhaustive]
+pub struct ItemsResult {
+    pub configs: Vec<Item>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+impl RevisionResult for ItemsResult {
+    fn get_revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Item {
+    pub name: String,
+    pub value: String,
+    pub version: u64,
+}
+
+impl Item {
+    pub fn new(name: &str, value: &str, version: u64) -> Item {
+        Item {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            version,
+        }
+    }
+
+    pub fn json<T>(&self) -> Result<T, serde_json::Error>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        serde_json::from_str(&self.value)
+    }
+
+    pub fn yaml<T>(&self) -> Result<T, serde_yaml::Error>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        serde_yaml::from_str(&self.value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct ServiceResult {
+    pub service: Service,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// What a service deletion would remove, by zone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct DeleteServiceSummary {
+    #[serde(default)]
+    pub zones: HashMap<String, Vec<ServiceEndpoint>>,
+}
+
+impl ServiceResult {
+    pub fn new(service: Service, revision: u64) -> ServiceResult {
+        ServiceResult { service, revision }
+    }
+}
+
+impl RevisionResult for ServiceResult {
+    fn get_revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct LeaseGrantResult {
+    pub lease_id: i64,
+    pub ttl: i64,
+    pub new_app_node: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct LeaseInfo {
+    pub lease_id: i64,
+    /// Remaining TTL in seconds.
+    pub ttl: i64,
+    /// Keys attached to the lease.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+impl LeaseGrantResult {
+    /// Whether this grant brought the app node online, i.e. the node was newly created.
+    pub fn is_new_node(&self) -> bool {
+        self.new_app_node == Some(true)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct PlugResult {
+    pub lease_id: i64,
+    pub ttl: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct ServiceDescWatchResult {
+    pub events: Option<Vec<ServiceDescEvent>>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+impl RevisionResult for ServiceDescWatchResult {
+    fn get_revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct ServiceWithRawZone {
+    pub service: String,
+    #[serde(default)]
+    pub zones: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct ServiceWithRawZoneResult {
+    pub service: ServiceWithRawZone,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+impl RevisionResult for ServiceWithRawZoneResult {
+    fn get_revision(&self) -> u64 {
+        self.revision
+    }
+}
@@ -1,10 +1,17 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::{collections::HashMap, net::SocketAddr};
 
 use crate::addr_serde;
-use crate::RevisionResult;
+use crate::error::Error;
+use crate::{random_u64, RevisionResult};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ServiceDesc {
     pub service: String,
     pub zone: String,
@@ -14,6 +21,100 @@ pub struct ServiceDesc {
     pub description: Option<String>,
 }
 
+impl ServiceDesc {
+    pub fn key(&self) -> ServiceKey {
+        ServiceKey::new(&self.service, &self.zone)
+    }
+
+    pub fn builder(service: &str, zone: &str) -> ServiceDescBuilder {
+        ServiceDescBuilder {
+            desc: ServiceDesc {
+                service: service.to_owned(),
+                zone: zone.to_owned(),
+                typ: None,
+                proto: None,
+                description: None,
+            },
+        }
+    }
+}
+
+pub struct ServiceDescBuilder {
+    desc: ServiceDesc,
+}
+
+impl ServiceDescBuilder {
+    pub fn typ(mut self, typ: &str) -> ServiceDescBuilder {
+        self.desc.typ = Some(typ.to_owned());
+        self
+    }
+
+    pub fn proto(mut self, proto: &str) -> ServiceDescBuilder {
+        self.desc.proto = Some(proto.to_owned());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> ServiceDescBuilder {
+        self.desc.description = Some(description.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<ServiceDesc, Error> {
+        if self.desc.service.is_empty() {
+            return Err(Error::Other("empty service name".to_owned()));
+        }
+        if self.desc.zone.is_empty() {
+            return Err(Error::Other(format!(
+                "empty zone of service {}",
+                self.desc.service
+            )));
+        }
+        if self.desc.zone.contains(':') {
+            return Err(Error::Other(format!(
+                "invalid zone of service {}: {}",
+                self.desc.service, self.desc.zone
+            )));
+        }
+        Ok(self.desc)
+    }
+}
+
+/// A service in a zone, formatted and parsed as `service:zone`. The service
+/// name may contain colons, the zone may not: parsing splits at the last one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ServiceKey {
+    pub service: String,
+    pub zone: String,
+}
+
+impl ServiceKey {
+    pub fn new(service: &str, zone: &str) -> ServiceKey {
+        ServiceKey {
+            service: service.to_owned(),
+            zone: zone.to_owned(),
+        }
+    }
+}
+
+impl Display for ServiceKey {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}:{}", self.service, self.zone)
+    }
+}
+
+impl FromStr for ServiceKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ServiceKey, Error> {
+        match s.rsplit_once(':') {
+            Some((service, zone)) if !service.is_empty() && !zone.is_empty() => {
+                Ok(ServiceKey::new(service, zone))
+            }
+            _ => Err(Error::Other(format!("invalid service key: {}", s))),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServiceDescEvent {
     pub event_type: String,
@@ -25,26 +126,74 @@ impl ServiceDescEvent {
     pub const DELETE: &'static str = "delete";
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Service {
     pub service: String,
+    #[serde(default)]
     pub zones: HashMap<String, ZoneService>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ZoneService {
     #[serde(flatten)]
     pub desc: ServiceDesc,
+    #[serde(default)]
     pub endpoints: Vec<ServiceEndpoint>,
 }
 
+impl Service {
+    /// Zones in lexicographic order, for stable logs and routing.
+    pub fn zones_sorted(&self) -> Vec<(&str, &ZoneService)> {
+        let mut zones: Vec<(&str, &ZoneService)> =
+            self.zones.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        zones.sort_by_key(|(k, _)| *k);
+        zones
+    }
+
+    /// Endpoints added and removed since `previous`, by zone. Endpoints are
+    /// compared by address only, so a config change is not reported.
+    pub fn diff(&self, previous: &Service) -> ServiceDiff {
+        let (current, previous) = (self.zone_addresses(), previous.zone_addresses());
+        ServiceDiff {
+            added: current.difference(&previous).cloned().collect(),
+            removed: previous.difference(&current).cloned().collect(),
+        }
+    }
+
+    /// Keeps only the endpoints for which `f` returns true, in every zone.
+    pub fn retain_endpoints<F: FnMut(&ServiceEndpoint) -> bool>(&mut self, mut f: F) {
+        for zone in self.zones.values_mut() {
+            zone.endpoints.retain(|e| f(e));
+        }
+    }
+
+    fn zone_addresses(&self) -> HashSet<(String, SocketAddr)> {
+        self.zones
+            .iter()
+            .flat_map(|(zone, z)| z.addresses().map(move |addr| (zone.clone(), addr)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceDiff {
+    pub added: HashSet<(String, SocketAddr)>,
+    pub removed: HashSet<(String, SocketAddr)>,
+}
+
+impl ServiceDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
 impl ZoneService {
     pub fn addresses<'a>(&'a self) -> impl Iterator<Item = SocketAddr> + 'a {
         self.endpoints.iter().map(|e| e.address)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ServiceEndpoint {
     #[serde(
         serialize_with = "addr_serde::serialize_address",
@@ -55,6 +204,34 @@ pub struct ServiceEndpoint {
 }
 
 impl ServiceEndpoint {
+    pub fn new(address: SocketAddr, config: Option<String>) -> ServiceEndpoint {
+        ServiceEndpoint { address, config }
+    }
+
+    /// An endpoint whose config is `config` encoded as json, the counterpart
+    /// of `de_config_json`.
+    pub fn with_config<T: Serialize>(
+        address: SocketAddr,
+        config: &T,
+    ) -> Result<ServiceEndpoint, Error> {
+        let config = serde_json::to_string(config).map_err(Error::from)?;
+        Ok(ServiceEndpoint::new(address, Some(config)))
+    }
+
+    /// Whether both are the same endpoint, whatever their config.
+    pub fn same_address(&self, other: &ServiceEndpoint) -> bool {
+        self.address == other.address
+    }
+
+    /// By convention an endpoint is unhealthy when its json config has
+    /// `"healthy": false`; endpoints without such a field are healthy.
+    pub fn is_healthy(&self) -> bool {
+        match self.de_config_json::<serde_json::Value>() {
+            Ok(Some(config)) => config.get("healthy").and_then(|h| h.as_bool()) != Some(false),
+            _ => true,
+        }
+    }
+
     pub fn de_config_json<'de, T>(&'de self) -> Result<Option<T>, serde_json::Error>
     where
         T: Deserialize<'de>,
@@ -63,6 +240,41 @@ impl ServiceEndpoint {
     }
 }
 
+/// Rejects an empty endpoint list or one with duplicate addresses.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) fn check_endpoints(endpoints: &[ServiceEndpoint]) -> Result<(), Error> {
+    if endpoints.is_empty() {
+        return Err(Error::Other("no endpoint".to_owned()));
+    }
+    let mut seen = HashSet::new();
+    for endpoint in endpoints {
+        if !seen.insert(endpoint.address) {
+            return Err(Error::Other(format!(
+                "duplicate endpoint address: {}",
+                endpoint.address
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for ServiceEndpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ServiceEndpoint, Error> {
+        let address = addr_serde::parse_address(s).map_err(Error::Other)?;
+        Ok(ServiceEndpoint::new(address, None))
+    }
+}
+
+impl TryFrom<&str> for ServiceEndpoint {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<ServiceEndpoint, Error> {
+        s.parse()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AppNode {
     pub label: Option<String>,
@@ -71,8 +283,11 @@ pub struct AppNode {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
 pub struct AppNodes {
+    #[serde(default)]
     pub nodes: HashMap<String, String>,
+    #[serde(default)]
     pub revision: u64,
 }
 
@@ -81,3 +296,305 @@ impl RevisionResult for AppNodes {
         self.revision
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    Random,
+    /// Random, proportional to the `weight` in the endpoint's json config (default 1).
+    Weighted,
+}
+
+/// Picks endpoints of a service, skipping zones/addresses marked unhealthy.
+/// Built from a `ServiceCache` by `ServiceBalancer::from_cache`, it follows the
+/// cache's watch updates.
+pub struct ServiceBalancer {
+    strategy: BalanceStrategy,
+    // 与 ServiceCache 共享, 未拿到 service 前为 None
+    service: Arc<RwLock<Option<Service>>>,
+    next: AtomicUsize,
+    unhealthy_zones: RwLock<HashSet<String>>,
+    unhealthy_addrs: RwLock<HashSet<SocketAddr>>,
+}
+
+impl ServiceBalancer {
+    pub fn new(service: Service, strategy: BalanceStrategy) -> ServiceBalancer {
+        Self::shared(Arc::new(RwLock::new(Some(service))), strategy)
+    }
+
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn shared(
+        service: Arc<RwLock<Option<Service>>>,
+        strategy: BalanceStrategy,
+    ) -> ServiceBalancer {
+        ServiceBalancer {
+            strategy,
+            service,
+            next: AtomicUsize::new(0),
+            unhealthy_zones: RwLock::new(HashSet::new()),
+            unhealthy_addrs: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Replaces the service; for a balancer built from a `ServiceCache`, the
+    /// next watch update replaces it again.
+    pub fn update(&self, service: Service) {
+        *self.service.write().unwrap() = Some(service);
+    }
+
+    pub fn set_zone_healthy(&self, zone: &str, healthy: bool) {
+        let mut zones = self.unhealthy_zones.write().unwrap();
+        if healthy {
+            zones.remove(zone);
+        } else {
+            zones.insert(zone.to_owned());
+        }
+    }
+
+    pub fn set_addr_healthy(&self, addr: SocketAddr, healthy: bool) {
+        let mut addrs = self.unhealthy_addrs.write().unwrap();
+        if healthy {
+            addrs.remove(&addr);
+        } else {
+            addrs.insert(addr);
+        }
+    }
+
+    pub fn pick(&self) -> Option<SocketAddr> {
+        let candidates = self.candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+        match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed);
+                Some(candidates[n % candidates.len()].0)
+            }
+            BalanceStrategy::Random => Some(candidates[random_u64() as usize % candidates.len()].0),
+            BalanceStrategy::Weighted => {
+                let total: u64 = candidates.iter().map(|(_, w)| w).sum();
+                if total == 0 {
+                    return None;
+                }
+                let mut n = random_u64() % total;
+                for (addr, weight) in candidates {
+                    if n < weight {
+                        return Some(addr);
+                    }
+                    n -= weight;
+                }
+                None
+            }
+        }
+    }
+
+    fn candidates(&self) -> Vec<(SocketAddr, u64)> {
+        let service = self.service.read().unwrap();
+        let service = match &*service {
+            Some(service) => service,
+            None => return Vec::new(),
+        };
+        let unhealthy_zones = self.unhealthy_zones.read().unwrap();
+        let unhealthy_addrs = self.unhealthy_addrs.read().unwrap();
+        let mut zones: Vec<&String> = service
+            .zones
+            .keys()
+            .filter(|z| !unhealthy_zones.contains(*z))
+            .collect();
+        zones.sort();
+        zones
+            .into_iter()
+            .flat_map(|z| service.zones[z].endpoints.iter())
+            .filter(|e| !unhealthy_addrs.contains(&e.address))
+            .map(|e| (e.address, endpoint_weight(e)))
+            .collect()
+    }
+}
+
+fn endpoint_weight(endpoint: &ServiceEndpoint) -> u64 {
+    match endpoint.de_config_json::<serde_json::Value>() {
+        Ok(Some(config)) => config.get("weight").and_then(|w| w.as_u64()).unwrap_or(1),
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(zone: &str, addrs: &[&str]) -> (String, ZoneService) {
+        let endpoints = addrs
+            .iter()
+            .map(|a| ServiceEndpoint::new(a.parse().unwrap(), None))
+            .collect();
+        let desc = ServiceDesc::builder("demo.svc", zone).build().unwrap();
+        (zone.to_owned(), ZoneService { desc, endpoints })
+    }
+
+    fn service(zones: Vec<(String, ZoneService)>) -> Service {
+        Service {
+            service: "demo.svc".to_owned(),
+            zones: zones.into_iter().collect(),
+        }
+    }
+
+    fn weighted(zone: &str, endpoints: &[(&str, u64)]) -> (String, ZoneService) {
+        let endpoints = endpoints
+            .iter()
+            .map(|(a, w)| {
+                ServiceEndpoint::new(a.parse().unwrap(), Some(format!("{{\"weight\": {}}}", w)))
+            })
+            .collect();
+        let desc = ServiceDesc::builder("demo.svc", zone).build().unwrap();
+        (zone.to_owned(), ZoneService { desc, endpoints })
+    }
+
+    fn picks(balancer: &ServiceBalancer, n: usize) -> HashMap<SocketAddr, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..n {
+            *counts.entry(balancer.pick().unwrap()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn addr(a: &str) -> SocketAddr {
+        a.parse().unwrap()
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> HashSet<(String, SocketAddr)> {
+        entries
+            .iter()
+            .map(|(zone, addr)| (zone.to_string(), addr.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_zone_added() {
+        let previous = service(vec![zone("z1", &["10.0.0.1:80"])]);
+        let current = service(vec![
+            zone("z1", &["10.0.0.1:80"]),
+            zone("z2", &["10.0.0.2:80", "10.0.0.3:80"]),
+        ]);
+        let diff = current.diff(&previous);
+        assert_eq!(
+            diff.added,
+            entries(&[("z2", "10.0.0.2:80"), ("z2", "10.0.0.3:80")])
+        );
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_zone_removed() {
+        let previous = service(vec![
+            zone("z1", &["10.0.0.1:80"]),
+            zone("z2", &["10.0.0.2:80"]),
+        ]);
+        let current = service(vec![zone("z1", &["10.0.0.1:80"])]);
+        let diff = current.diff(&previous);
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed, entries(&[("z2", "10.0.0.2:80")]));
+    }
+
+    #[test]
+    fn diff_endpoint_churn() {
+        let previous = service(vec![zone("z1", &["10.0.0.1:80", "10.0.0.2:80"])]);
+        let current = service(vec![zone("z1", &["10.0.0.2:80", "10.0.0.3:80"])]);
+        let diff = current.diff(&previous);
+        assert_eq!(diff.added, entries(&[("z1", "10.0.0.3:80")]));
+        assert_eq!(diff.removed, entries(&[("z1", "10.0.0.1:80")]));
+    }
+
+    #[test]
+    fn diff_ignores_config_and_detects_zone_move() {
+        let mut previous = service(vec![zone("z1", &["10.0.0.1:80"])]);
+        let current = previous.clone();
+        previous.zones.get_mut("z1").unwrap().endpoints[0].config = Some("{}".to_owned());
+        assert!(current.diff(&previous).is_empty());
+
+        // 同一地址换了 zone, 视为一删一增
+        let moved = service(vec![zone("z2", &["10.0.0.1:80"])]);
+        let diff = moved.diff(&current);
+        assert_eq!(diff.added, entries(&[("z2", "10.0.0.1:80")]));
+        assert_eq!(diff.removed, entries(&[("z1", "10.0.0.1:80")]));
+    }
+
+    #[test]
+    fn round_robin_cycles_in_zone_order() {
+        let balancer = ServiceBalancer::new(
+            service(vec![
+                zone("z2", &["10.0.0.3:80"]),
+                zone("z1", &["10.0.0.1:80", "10.0.0.2:80"]),
+            ]),
+            BalanceStrategy::RoundRobin,
+        );
+        let picked: Vec<SocketAddr> = (0..4).map(|_| balancer.pick().unwrap()).collect();
+        assert_eq!(
+            picked,
+            vec![
+                addr("10.0.0.1:80"),
+                addr("10.0.0.2:80"),
+                addr("10.0.0.3:80"),
+                addr("10.0.0.1:80"),
+            ]
+        );
+    }
+
+    #[test]
+    fn random_picks_every_endpoint() {
+        let balancer = ServiceBalancer::new(
+            service(vec![zone("z1", &["10.0.0.1:80", "10.0.0.2:80"])]),
+            BalanceStrategy::Random,
+        );
+        let counts = picks(&balancer, 200);
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn weighted_follows_weights() {
+        let balancer = ServiceBalancer::new(
+            service(vec![weighted(
+                "z1",
+                &[("10.0.0.1:80", 0), ("10.0.0.2:80", 1), ("10.0.0.3:80", 9)],
+            )]),
+            BalanceStrategy::Weighted,
+        );
+        let counts = picks(&balancer, 1000);
+        assert!(!counts.contains_key(&addr("10.0.0.1:80")));
+        assert!(counts[&addr("10.0.0.3:80")] > counts[&addr("10.0.0.2:80")] * 3);
+
+        let balancer = ServiceBalancer::new(
+            service(vec![weighted("z1", &[("10.0.0.1:80", 0)])]),
+            BalanceStrategy::Weighted,
+        );
+        assert_eq!(balancer.pick(), None);
+    }
+
+    #[test]
+    fn unhealthy_endpoints_skipped() {
+        let balancer = ServiceBalancer::new(
+            service(vec![
+                zone("z1", &["10.0.0.1:80", "10.0.0.2:80"]),
+                zone("z2", &["10.0.0.3:80"]),
+            ]),
+            BalanceStrategy::RoundRobin,
+        );
+        balancer.set_zone_healthy("z2", false);
+        balancer.set_addr_healthy(addr("10.0.0.1:80"), false);
+        assert_eq!(
+            picks(&balancer, 10).keys().collect::<Vec<_>>(),
+            vec![&addr("10.0.0.2:80")]
+        );
+
+        balancer.set_addr_healthy(addr("10.0.0.2:80"), false);
+        assert_eq!(balancer.pick(), None);
+        balancer.set_zone_healthy("z2", true);
+        assert_eq!(balancer.pick(), Some(addr("10.0.0.3:80")));
+    }
+
+    #[test]
+    fn empty_balancer_picks_none() {
+        let balancer =
+            ServiceBalancer::shared(Arc::new(RwLock::new(None)), BalanceStrategy::Random);
+        assert_eq!(balancer.pick(), None);
+    }
+}
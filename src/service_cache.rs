@@ -0,0 +1,157 @@
+use crate::client::Client;
+use crate::error::Error;
+use crate::service::{BalanceStrategy, Service, ServiceBalancer};
+use crate::watcher::WatchHandle;
+use futures::prelude::*;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::spawn;
+use tokio::task::spawn_blocking;
+
+const WATCH_INTERVAL: u64 = 60;
+
+/// Locally cached service topology kept up to date by a background watch.
+/// Failed watches are retried, the last known value is served meanwhile.
+/// With `Config::topology_snapshot_path` set, the last known value is also
+/// persisted and reloaded on startup.
+pub struct ServiceCache {
+    _handle: WatchHandle,
+    service: Arc<RwLock<Option<Service>>>,
+}
+
+impl ServiceCache {
+    pub(crate) fn new(client: &Client, service: &str) -> ServiceCache {
+        let (handle, mut rx) = client
+            .watch_service(service, None, None, Duration::from_secs(WATCH_INTERVAL))
+            .split();
+        let snapshot = client
+            .config()
+            .topology_snapshot_path
+            .as_ref()
+            .map(|dir| snapshot_file(Path::new(dir), service));
+        let initial = snapshot.as_deref().and_then(|path| match load_snapshot(path) {
+            Ok(service) => service,
+            Err(e) => {
+                warn!(target: "xbus::watch", "load topology snapshot {} fail: {}", path.display(), e);
+                None
+            }
+        });
+        let cached = Arc::new(RwLock::new(initial));
+        let updating = cached.clone();
+        spawn(async move {
+            while let Some(result) = rx.next().await {
+                if updating.read().unwrap().as_ref() == Some(&result.service) {
+                    continue;
+                }
+                if let Some(ref path) = snapshot {
+                    let (path, service) = (path.clone(), result.service.clone());
+                    let saved = spawn_blocking(move || save_snapshot(&path, &service));
+                    if let Ok(Err(e)) = saved.await {
+                        warn!(target: "xbus::watch", "save topology snapshot fail: {}", e);
+                    }
+                }
+                *updating.write().unwrap() = Some(result.service);
+            }
+        });
+        ServiceCache {
+            _handle: handle,
+            service: cached,
+        }
+    }
+
+    pub fn service(&self) -> Option<Service> {
+        self.service.read().unwrap().clone()
+    }
+
+    pub fn resolve(&self, zone: &str) -> Vec<SocketAddr> {
+        match &*self.service.read().unwrap() {
+            Some(service) => service
+                .zones
+                .get(zone)
+                .map(|z| z.addresses().collect())
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl ServiceBalancer {
+    /// A balancer over `cache`'s service, following its watch updates; picks
+    /// nothing until the service is first fetched.
+    pub fn from_cache(cache: &ServiceCache, strategy: BalanceStrategy) -> ServiceBalancer {
+        ServiceBalancer::shared(cache.service.clone(), strategy)
+    }
+}
+
+fn snapshot_file(dir: &Path, service: &str) -> PathBuf {
+    dir.join(format!("{}.json", service.replace('/', "_")))
+}
+
+fn load_snapshot(path: &Path) -> Result<Option<Service>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// 先写临时文件再 rename, 避免进程中途退出留下不完整的快照
+fn save_snapshot(path: &Path, service: &Service) -> Result<(), Error> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(service)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mock::MockTransport;
+    use crate::service::{ServiceDesc, ServiceEndpoint, ZoneService};
+    use hyper::Method;
+    use serde_json::json;
+
+    fn service(addr: &str) -> Service {
+        let zone = ZoneService {
+            desc: ServiceDesc::builder("demo.svc", "z1").build().unwrap(),
+            endpoints: vec![ServiceEndpoint::new(addr.parse().unwrap(), None)],
+        };
+        Service {
+            service: "demo.svc".to_owned(),
+            zones: vec![("z1".to_owned(), zone)].into_iter().collect(),
+        }
+    }
+
+    async fn wait_pick(balancer: &ServiceBalancer, addr: &str) {
+        let addr: SocketAddr = addr.parse().unwrap();
+        while balancer.pick() != Some(addr) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn balancer_follows_cache() {
+        let transport = MockTransport::new();
+        let path = "/api/v1/services/demo.svc";
+        transport.respond(
+            Method::GET,
+            path,
+            &json!({"service": service("10.0.0.1:80"), "revision": 1}),
+        );
+        let client = Client::from_responses(Config::new("http://xbus"), transport.clone()).unwrap();
+        let cache = client.service_cache("demo.svc");
+        let balancer = ServiceBalancer::from_cache(&cache, BalanceStrategy::RoundRobin);
+        wait_pick(&balancer, "10.0.0.1:80").await;
+
+        transport.respond(
+            Method::GET,
+            path,
+            &json!({"service": service("10.0.0.2:80"), "revision": 2}),
+        );
+        wait_pick(&balancer, "10.0.0.2:80").await;
+    }
+}
@@ -1,39 +1,94 @@
-use super::service::{AppNode, ServiceDesc, ServiceEndpoint};
+use super::service::{check_endpoints, AppNode, ServiceDesc, ServiceEndpoint, ServiceKey};
+use crate::backoff::{jitter, Backoff, ConstantBackoff, ExponentialBackoff};
 use crate::client::{Client, LeaseGrantResult, PlugResult};
 use crate::error::Error;
 use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::iter::FromIterator;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::spawn;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 
 const GRANT_RETRY_INTERVAL: u64 = 5;
+const GRANT_RETRY_MAX_INTERVAL: u64 = 120;
+
+/// Decides whether a non-retryable plug error should be retried, given the
+/// error and the number of attempts so far; `None` cancels the plug.
+pub type RetryPolicy = Arc<dyn Fn(&Error, u32) -> Option<Duration> + Send + Sync>;
+
+/// Keeper state changes, see `ServiceKeeper::notify_events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeeperEvent {
+    /// All kept services were plugged again, after a new lease or an endpoint update.
+    Replugged { count: usize, lease_id: i64 },
+}
 
 enum Cmd {
     Start,
-    UpdateEndpoint(ServiceEndpoint),
+    UpdateEndpoints(Vec<ServiceEndpoint>),
+    UpdateEndpointConfig(SocketAddr, Option<String>),
     Plug(ServiceDesc, oneshot::Sender<Result<(), Error>>, bool),
-    Unplug(String, String),
-    Cancel(String, String),
+    RetryPlug(ServiceDesc, oneshot::Sender<Result<(), Error>>, u32),
+    PlugLeaseExpired(ServiceDesc, oneshot::Sender<Result<(), Error>>, i64),
+    Unplug(ServiceKey),
+    Cancel(ServiceKey),
     Clear(oneshot::Sender<()>),
     RevokeAndClose(oneshot::Sender<()>),
     NotifyNodeOnline(mpsc::UnboundedSender<bool>),
+    NotifyReady(oneshot::Sender<()>),
+    NotifyEvents(mpsc::UnboundedSender<KeeperEvent>),
+    LastKeepalive(oneshot::Sender<Option<Instant>>),
 }
 
 pub struct ServiceKeeper {
     cmd_tx: mpsc::UnboundedSender<Cmd>,
 }
 
+impl Debug for ServiceKeeper {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("ServiceKeeper")
+            .field("closed", &self.cmd_tx.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
 impl ServiceKeeper {
     pub fn new(
         client: &Client,
         ttl: Option<i64>,
         app_node: Option<AppNode>,
         endpoint: ServiceEndpoint,
+        retry_policy: Option<RetryPolicy>,
+    ) -> ServiceKeeper {
+        Self::spawn(client, ttl, app_node, vec![endpoint], retry_policy)
+    }
+
+    /// Keeps services plugged on several endpoints, each with its own config.
+    pub fn with_endpoints(
+        client: &Client,
+        ttl: Option<i64>,
+        app_node: Option<AppNode>,
+        endpoints: Vec<ServiceEndpoint>,
+        retry_policy: Option<RetryPolicy>,
+    ) -> Result<ServiceKeeper, Error> {
+        check_endpoints(&endpoints)?;
+        Ok(Self::spawn(client, ttl, app_node, endpoints, retry_policy))
+    }
+
+    fn spawn(
+        client: &Client,
+        ttl: Option<i64>,
+        app_node: Option<AppNode>,
+        endpoints: Vec<ServiceEndpoint>,
+        retry_policy: Option<RetryPolicy>,
     ) -> ServiceKeeper {
         let (tx, rx) = mpsc::unbounded();
         spawn(KeepTask::new(
@@ -42,7 +97,8 @@ impl ServiceKeeper {
             rx,
             ttl,
             app_node,
-            endpoint,
+            endpoints,
+            retry_policy,
         ));
         ServiceKeeper { cmd_tx: tx }
     }
@@ -52,22 +108,61 @@ impl ServiceKeeper {
     }
 
     pub fn update_endpoint(&self, endpoint: ServiceEndpoint) {
-        let _ = self.cmd_tx.unbounded_send(Cmd::UpdateEndpoint(endpoint));
+        let _ = self
+            .cmd_tx
+            .unbounded_send(Cmd::UpdateEndpoints(vec![endpoint]));
+    }
+
+    pub fn update_endpoints(&self, endpoints: Vec<ServiceEndpoint>) -> Result<(), Error> {
+        check_endpoints(&endpoints)?;
+        let _ = self.cmd_tx.unbounded_send(Cmd::UpdateEndpoints(endpoints));
+        Ok(())
     }
 
+    /// Forwards each endpoint as `update_endpoint`; closing the sink leaves the keeper running.
+    pub fn endpoint_sink(&self) -> impl Sink<ServiceEndpoint, Error = Error> {
+        self.cmd_tx
+            .clone()
+            .sink_map_err(|_| Error::Other("service keeper closed".to_owned()))
+            .with(|endpoint| future::ok(Cmd::UpdateEndpoints(vec![endpoint])))
+    }
+
+    /// Replaces the config of the endpoint at `address`, other endpoints keep theirs.
+    pub fn update_endpoint_config(&self, address: SocketAddr, config: Option<String>) {
+        let _ = self
+            .cmd_tx
+            .unbounded_send(Cmd::UpdateEndpointConfig(address, config));
+    }
+
+    #[must_use = "the plug is queued anyway, but its result is lost"]
     pub fn plug(&self, service: &ServiceDesc) -> impl Future<Output = Result<(), Error>> {
         self.plug_replaceable(service, false)
     }
 
+    /// Like `plug`, moving `service` instead of cloning it.
+    #[must_use = "the plug is queued anyway, but its result is lost"]
+    pub fn plug_owned(&self, service: ServiceDesc) -> impl Future<Output = Result<(), Error>> {
+        self.send_plug(service, false)
+    }
+
+    #[must_use = "the plug is queued anyway, but its result is lost"]
     pub fn plug_replaceable(
         &self,
         service: &ServiceDesc,
         replaceable: bool,
     ) -> impl Future<Output = Result<(), Error>> {
+        self.send_plug(service.clone(), replaceable)
+    }
+
+    fn send_plug(
+        &self,
+        service: ServiceDesc,
+        replaceable: bool,
+    ) -> BoxFuture<'static, Result<(), Error>> {
         let (tx, rx) = oneshot::channel();
         if self
             .cmd_tx
-            .unbounded_send(Cmd::Plug(service.clone(), tx, replaceable))
+            .unbounded_send(Cmd::Plug(service, tx, replaceable))
             .is_err()
         {
             return future::err(Error::Other("keep task closed".to_string())).boxed();
@@ -80,12 +175,30 @@ impl ServiceKeeper {
         .boxed()
     }
 
+    /// Plugs `service`, unplugging it again when the returned guard is dropped.
+    #[must_use = "the guard unplugs the service when dropped"]
+    pub fn plug_guarded(
+        &self,
+        service: &ServiceDesc,
+    ) -> impl Future<Output = Result<PlugGuard, Error>> {
+        // 只在 plug 成功后创建 guard, 失败时 drop 不能 unplug 已有的注册
+        let (cmd_tx, key) = (self.cmd_tx.clone(), service.key());
+        self.plug(service).map(move |r| {
+            r.map(|_| PlugGuard {
+                cmd_tx,
+                key: Some(key),
+            })
+        })
+    }
+
     pub fn unplug<S: Into<String>>(&self, service: S, zone: S) {
-        let _ = self
-            .cmd_tx
-            .unbounded_send(Cmd::Unplug(service.into(), zone.into()));
+        let _ = self.cmd_tx.unbounded_send(Cmd::Unplug(ServiceKey {
+            service: service.into(),
+            zone: zone.into(),
+        }));
     }
 
+    #[must_use = "await the receiver to know when services are unplugged"]
     pub fn clear(&self) -> oneshot::Receiver<()> {
         let (tx, rx) = oneshot::channel();
         if let Err(r) = self.cmd_tx.unbounded_send(Cmd::Clear(tx)) {
@@ -98,6 +211,7 @@ impl ServiceKeeper {
         rx
     }
 
+    #[must_use = "await the receiver to know when the lease is revoked"]
     pub fn close(&self) -> oneshot::Receiver<()> {
         let (tx, rx) = oneshot::channel();
         if let Err(r) = self.cmd_tx.unbounded_send(Cmd::RevokeAndClose(tx)) {
@@ -113,6 +227,50 @@ impl ServiceKeeper {
     pub fn notify_node_online(&self, tx: mpsc::UnboundedSender<bool>) {
         let _ = self.cmd_tx.unbounded_send(Cmd::NotifyNodeOnline(tx));
     }
+
+    pub fn notify_events(&self, tx: mpsc::UnboundedSender<KeeperEvent>) {
+        let _ = self.cmd_tx.unbounded_send(Cmd::NotifyEvents(tx));
+    }
+
+    /// Resolves once the lease is granted and services are plugged for the
+    /// first time, immediately if that already happened. Fails if the keeper
+    /// is closed before getting ready.
+    pub fn ready(&self) -> impl Future<Output = Result<(), Error>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.cmd_tx.unbounded_send(Cmd::NotifyReady(tx));
+        rx.map(|r| r.map_err(|_| Error::Other("keep task closed before ready".to_string())))
+    }
+
+    /// Like `ready`, failing with `Error::io_timeout()` if not ready within `dur`;
+    /// `ready`'s own error is returned as is if the keeper closes first.
+    pub fn ready_timeout(&self, dur: Duration) -> impl Future<Output = Result<(), Error>> {
+        timeout(dur, self.ready()).map(|r| r.map_err(|_| Error::io_timeout())?)
+    }
+
+    /// When the lease was last renewed successfully, `None` before the first
+    /// renewal or once the keeper is closed. For health checks, a value older
+    /// than the lease ttl means the keep loop is stuck or failing.
+    pub fn last_keepalive(&self) -> impl Future<Output = Option<Instant>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.cmd_tx.unbounded_send(Cmd::LastKeepalive(tx));
+        rx.map(|r| r.ok().flatten())
+    }
+}
+
+/// Unplugs its service on drop. The unplug is best effort: it is queued to the
+/// keep task without waiting for the server to acknowledge it.
+#[must_use = "dropping the guard unplugs the service"]
+pub struct PlugGuard {
+    cmd_tx: mpsc::UnboundedSender<Cmd>,
+    key: Option<ServiceKey>,
+}
+
+impl Drop for PlugGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let _ = self.cmd_tx.unbounded_send(Cmd::Unplug(key));
+        }
+    }
 }
 
 #[allow(clippy::type_complexity)]
@@ -121,18 +279,31 @@ struct KeepTask {
     started: bool,
     closing: bool,
     ttl: Option<i64>,
-    endpoint: ServiceEndpoint,
+    endpoints: Vec<ServiceEndpoint>,
     app_node: Option<AppNode>,
+    retry_policy: Option<RetryPolicy>,
     cmd_tx: mpsc::UnboundedSender<Cmd>,
     cmd_rx: mpsc::UnboundedReceiver<Cmd>,
-    services: HashMap<(String, String), ServiceDesc>,
+    services: HashMap<ServiceKey, ServiceDesc>,
     lease_result: Option<LeaseGrantResult>,
     lease_future: Option<Pin<Box<dyn Future<Output = Result<LeaseGrantResult, Error>> + Send>>>,
     replug_future: Option<Pin<Box<dyn Future<Output = Result<PlugResult, Error>> + Send>>>,
-    replug_backs: HashMap<(String, String), oneshot::Sender<Result<(), Error>>>,
-    lease_keep_future: Option<Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>>,
+    replug_count: usize,
+    replug_backs: HashMap<ServiceKey, oneshot::Sender<Result<(), Error>>>,
+    lease_keep_future:
+        Option<Pin<Box<dyn Future<Output = Result<Option<LeaseGrantResult>, Error>> + Send>>>,
     is_first_online: bool,
+    is_first_grant: bool,
+    grant_failures: u32,
+    grant_backoff: Box<dyn Backoff>,
+    replug_failures: u32,
+    replug_backoff: Box<dyn Backoff>,
+    lease_regrants: u32,
+    last_keepalive: Option<Instant>,
     online_notifiers: Vec<mpsc::UnboundedSender<bool>>,
+    event_notifiers: Vec<mpsc::UnboundedSender<KeeperEvent>>,
+    ready: bool,
+    ready_notifiers: Vec<oneshot::Sender<()>>,
 }
 
 impl KeepTask {
@@ -142,34 +313,66 @@ impl KeepTask {
         cmd_rx: mpsc::UnboundedReceiver<Cmd>,
         ttl: Option<i64>,
         app_node: Option<AppNode>,
-        endpoint: ServiceEndpoint,
+        endpoints: Vec<ServiceEndpoint>,
+        retry_policy: Option<RetryPolicy>,
     ) -> KeepTask {
+        let config = client.config();
+        // 未配置 backoff 时 grant 指数退避, replug 固定间隔
+        let grant_backoff = config.new_backoff().unwrap_or_else(|| {
+            Box::new(ExponentialBackoff::new(
+                Duration::from_secs(GRANT_RETRY_INTERVAL),
+                Duration::from_secs(GRANT_RETRY_MAX_INTERVAL),
+            ))
+        });
+        let replug_backoff = config.new_backoff().unwrap_or_else(|| {
+            Box::new(ConstantBackoff(Duration::from_secs(GRANT_RETRY_INTERVAL)))
+        });
         KeepTask {
             client: client.clone(),
             started: false,
             closing: false,
             ttl,
             app_node,
-            endpoint,
+            endpoints,
+            retry_policy,
             services: HashMap::new(),
             cmd_tx,
             cmd_rx,
             lease_result: None,
             lease_future: None,
             replug_future: None,
+            replug_count: 0,
             replug_backs: HashMap::new(),
             lease_keep_future: None,
             is_first_online: true,
+            is_first_grant: true,
+            grant_failures: 0,
+            grant_backoff,
+            replug_failures: 0,
+            replug_backoff,
+            lease_regrants: 0,
+            last_keepalive: None,
             online_notifiers: Vec::new(),
+            event_notifiers: Vec::new(),
+            ready: false,
+            ready_notifiers: Vec::new(),
         }
     }
 
-    fn new_lease(&mut self, delay_new: bool) {
+    fn new_lease(&mut self, retry_delay: Option<Duration>) {
         let app_node = self.app_node.clone();
-        if delay_new {
+        let delay = if let Some(delay) = retry_delay {
+            delay
+        } else if self.is_first_grant {
+            jitter(self.client.config().lease_grant_jitter)
+        } else {
+            Duration::from_secs(0)
+        };
+        self.is_first_grant = false;
+        if delay > Duration::from_secs(0) {
             let (client, ttl) = (self.client.clone(), self.ttl);
             self.lease_future = Some(
-                sleep(Duration::from_secs(GRANT_RETRY_INTERVAL))
+                sleep(delay)
                     .then(move |_| client.grant_lease(ttl, app_node.as_ref()))
                     .boxed(),
             );
@@ -181,79 +384,127 @@ impl KeepTask {
         self.replug_future = None;
     }
 
-    fn keep_lease(&mut self) {
+    fn keep_lease(&mut self, retry_delay: Option<Duration>) {
         self.lease_keep_future = None;
         if let Some(ref mut lease_result) = self.lease_result {
             let (client, lease_id) = (self.client.clone(), lease_result.lease_id);
+            let interval = Duration::from_secs(lease_result.ttl as u64 / 2);
+            let max_jitter = self.client.config().lease_grant_jitter.min(interval / 5);
+            let delay = retry_delay.unwrap_or_else(|| interval - jitter(max_jitter));
             self.lease_keep_future = Some(
-                sleep(Duration::from_secs(lease_result.ttl as u64 / 2))
-                    .then(move |_| client.keepalive_lease(lease_id))
+                sleep(delay)
+                    .then(move |_| client.keepalive_lease_with_result(lease_id))
                     .boxed(),
             );
         } else {
-            error!("missing lease result");
+            error!(target: "xbus::keeper", "missing lease result");
         }
     }
 
-    fn replug_all(&mut self, delay_plug: bool) {
+    fn replug_all(&mut self, retry_delay: Option<Duration>) {
         self.replug_future = None;
         if let Some(ref lease_result) = self.lease_result {
             if self.services.is_empty() {
-                info!("empty services, replug ignored");
+                info!(target: "xbus::keeper", "empty services, replug ignored");
                 return;
             }
 
             let services: Vec<ServiceDesc> = self.services.values().cloned().collect();
-            if delay_plug {
-                let (client, endpoint, lease_id) = (
-                    self.client.clone(),
-                    self.endpoint.clone(),
-                    lease_result.lease_id,
-                );
-                self.replug_future = Some(
-                    sleep(Duration::from_secs(GRANT_RETRY_INTERVAL))
-                        .then(move |_| {
-                            client.plug_all_services(&services, &endpoint, Some(lease_id), None)
-                        })
-                        .boxed(),
-                );
+            self.replug_count = services.len();
+            let plugging = plug_services(
+                &self.client,
+                &services,
+                &self.endpoints,
+                lease_result.lease_id,
+            );
+            if let Some(delay) = retry_delay {
+                self.replug_future = Some(sleep(delay).then(move |_| plugging).boxed());
             } else {
-                self.replug_future = Some(
-                    self.client
-                        .plug_all_services(
-                            &services,
-                            &self.endpoint,
-                            Some(lease_result.lease_id),
-                            None,
-                        )
-                        .boxed(),
-                );
+                self.replug_future = Some(plugging);
             }
         } else {
-            error!("missing lease result");
+            error!(target: "xbus::keeper", "missing lease result");
         }
     }
 
-    fn plug_one(&mut self, service: ServiceDesc, tx: oneshot::Sender<Result<(), Error>>) {
+    fn replug_under_lease(&mut self) {
+        // without a lease, the pending grant will replug with the new endpoint
+        if !self.services.is_empty() && self.started && self.lease_result.is_some() {
+            self.replug_all(None);
+        }
+    }
+
+    fn plug_one(
+        &mut self,
+        service: ServiceDesc,
+        tx: oneshot::Sender<Result<(), Error>>,
+        attempts: u32,
+    ) {
         if let Some(ref lease_result) = self.lease_result {
-            let mut cmd_tx = self.cmd_tx.clone();
-            spawn(
+            let (cmd_tx, retry_policy) = (self.cmd_tx.clone(), self.retry_policy.clone());
+            let lease_id = lease_result.lease_id;
+            let plugging = if self.endpoints.len() == 1 {
                 self.client
-                    .plug_service(&service, &self.endpoint, None, Some(lease_result.lease_id))
-                    .map(move |r| match r {
-                        Ok(_) => {
-                            let _ = tx.send(Ok(()));
-                        }
-                        Err(e) => {
+                    .plug_service(
+                        &service,
+                        &self.endpoints[0],
+                        None,
+                        Some(lease_result.lease_id),
+                    )
+                    .boxed()
+            } else {
+                plug_services(
+                    &self.client,
+                    std::slice::from_ref(&service),
+                    &self.endpoints,
+                    lease_result.lease_id,
+                )
+            };
+            spawn(plugging.then(move |r| async move {
+                match r {
+                    Ok(_) => {
+                        let _ = tx.send(Ok(()));
+                    }
+                    Err(e) if e.is_lease_not_found() => {
+                        // lease 过期不是 service 本身的问题, 重新申请 lease 后再 plug
+                        let _ = cmd_tx.unbounded_send(Cmd::PlugLeaseExpired(service, tx, lease_id));
+                    }
+                    Err(e) => {
+                        if !e.can_retry() || e.retry_after().is_some() {
+                            // 由 policy 决定是否重试, 服务端给了 Retry-After 时按其等待;
+                            // 没有 policy 时 Retry-After 总是等待后重试
+                            let delay = match retry_policy {
+                                Some(p) => {
+                                    p(&e, attempts + 1).map(|d| e.retry_after().unwrap_or(d))
+                                }
+                                None => e.retry_after(),
+                            };
+                            if let Some(delay) = delay {
+                                warn!(
+                                    target: "xbus::keeper",
+                                    "plug service {} fail, retry in {:?}: {}",
+                                    service.key(),
+                                    delay,
+                                    e
+                                );
+                                sleep(delay).await;
+                                let _ = cmd_tx.unbounded_send(Cmd::RetryPlug(
+                                    service,
+                                    tx,
+                                    attempts + 1,
+                                ));
+                                return;
+                            }
                             if !e.can_retry() {
-                                let _ = cmd_tx.send(Cmd::Cancel(service.service, service.zone));
+                                let _ = cmd_tx.unbounded_send(Cmd::Cancel(service.key()));
                             }
-                            let _ = tx.send(Err(e));
                         }
-                    }),
-            );
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }));
         } else {
-            error!("missing lease result");
+            error!(target: "xbus::keeper", "missing lease result");
         }
     }
 
@@ -264,35 +515,46 @@ impl KeepTask {
                     self.started = true;
                     if self.lease_result.is_none() {
                         if self.lease_future.is_none() {
-                            self.new_lease(false);
+                            self.new_lease(None);
                         }
                     } else {
-                        self.replug_all(false);
+                        self.replug_all(None);
                     }
                 }
             }
-            Cmd::UpdateEndpoint(endpoint) => {
-                self.endpoint = endpoint;
-                if !self.services.is_empty() && self.started {
-                    self.new_lease(false);
+            Cmd::UpdateEndpoints(endpoints) => {
+                let address_changed = endpoints.len() != self.endpoints.len()
+                    || endpoints
+                        .iter()
+                        .zip(&self.endpoints)
+                        .any(|(a, b)| !a.same_address(b));
+                self.endpoints = endpoints;
+                if !address_changed {
+                    self.replug_under_lease();
+                } else if !self.services.is_empty() && self.started {
+                    self.new_lease(None);
+                }
+            }
+            Cmd::UpdateEndpointConfig(address, config) => {
+                if set_endpoint_config(&mut self.endpoints, address, config) {
+                    self.replug_under_lease();
+                } else {
+                    warn!(target: "xbus::keeper", "no endpoint at {}, config ignored", address);
                 }
             }
             Cmd::Plug(service, tx, replaceable) => {
-                let key = (service.service.clone(), service.zone.clone());
+                let key = service.key();
                 if self.services.contains_key(&key) && !replaceable {
-                    let _ = tx.send(Err(Error::Other(format!(
-                        "{}:{} has been plugged",
-                        key.0, key.1
-                    ))));
+                    let _ = tx.send(Err(Error::Other(format!("{} has been plugged", key))));
                 } else {
                     self.services.insert(key.clone(), service.clone());
                     if self.started {
                         if self.lease_result.is_some() {
-                            self.plug_one(service, tx);
+                            self.plug_one(service, tx, 0);
                         } else {
                             self.replug_backs.insert(key, tx);
                             if self.lease_future.is_none() {
-                                self.new_lease(false);
+                                self.new_lease(None);
                             }
                         }
                     } else {
@@ -300,23 +562,52 @@ impl KeepTask {
                     }
                 }
             }
-            Cmd::Unplug(service, zone) => {
-                let key = (service, zone);
+            Cmd::RetryPlug(service, tx, attempts) => {
+                let key = service.key();
+                if !self.services.contains_key(&key) {
+                    let _ = tx.send(Err(Error::Other(format!("{} has been unplugged", key))));
+                } else if self.lease_result.is_some() {
+                    self.plug_one(service, tx, attempts);
+                } else {
+                    self.replug_backs.insert(key, tx);
+                }
+            }
+            Cmd::PlugLeaseExpired(service, tx, lease_id) => {
+                let key = service.key();
+                let current = self.lease_result.as_ref().map(|r| r.lease_id);
+                if !self.services.contains_key(&key) {
+                    let _ = tx.send(Err(Error::Other(format!("{} has been unplugged", key))));
+                } else if current == Some(lease_id) {
+                    self.replug_backs.insert(key, tx);
+                    self.regrant_lease(lease_id);
+                } else if current.is_some() {
+                    self.plug_one(service, tx, 0);
+                } else {
+                    self.replug_backs.insert(key, tx);
+                }
+            }
+            Cmd::Unplug(key) => {
                 self.replug_backs.remove(&key);
                 if self.services.remove(&key).is_some() && self.started {
-                    spawn(
-                        self.client
-                            .unplug_service(&key.0, &key.1, &self.endpoint.address.to_string())
-                            .map(move |r| {
-                                if let Err(e) = r {
-                                    error!("unplug service {}:{} fail: {}", key.0, key.1, e);
-                                }
-                            }),
-                    );
+                    for endpoint in &self.endpoints {
+                        let key = key.clone();
+                        spawn(
+                            self.client
+                                .unplug_service(
+                                    &key.service,
+                                    &key.zone,
+                                    &endpoint.address.to_string(),
+                                )
+                                .map(move |r| {
+                                    if let Err(e) = r {
+                                        error!(target: "xbus::keeper", "unplug service {} fail: {}", key, e);
+                                    }
+                                }),
+                        );
+                    }
                 }
             }
-            Cmd::Cancel(service, zone) => {
-                let key = (service, zone);
+            Cmd::Cancel(key) => {
                 self.services.remove(&key);
                 self.replug_backs.remove(&key);
             }
@@ -334,6 +625,54 @@ impl KeepTask {
             Cmd::NotifyNodeOnline(tx) => {
                 self.online_notifiers.push(tx);
             }
+            Cmd::LastKeepalive(tx) => {
+                let _ = tx.send(self.last_keepalive);
+            }
+            Cmd::NotifyEvents(tx) => {
+                self.event_notifiers.push(tx);
+            }
+            Cmd::NotifyReady(tx) => {
+                if self.ready {
+                    let _ = tx.send(());
+                } else {
+                    self.ready_notifiers.push(tx);
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, event: KeeperEvent) {
+        self.event_notifiers
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    // 超时立即重试, 其他错误按 Retry-After 或 backoff 延迟
+    fn replug_retry_delay(&mut self, e: &Error) -> Option<Duration> {
+        if e.is_timeout() {
+            return None;
+        }
+        self.replug_failures += 1;
+        Some(
+            e.retry_after()
+                .unwrap_or_else(|| self.replug_backoff.next_delay(self.replug_failures)),
+        )
+    }
+
+    // plug 时 lease 已不存在, 按 grant_backoff 延迟重新申请, 持续失败时不会反复 grant
+    fn regrant_lease(&mut self, lease_id: i64) {
+        self.lease_regrants += 1;
+        let delay = self.grant_backoff.next_delay(self.lease_regrants);
+        warn!(
+            target: "xbus::keeper",
+            "lease {:x} not found on plug, granting a new one in {:?}", lease_id, delay
+        );
+        self.new_lease(Some(delay));
+    }
+
+    fn mark_ready(&mut self) {
+        self.ready = true;
+        for tx in self.ready_notifiers.drain(..) {
+            let _ = tx.send(());
         }
     }
 
@@ -350,7 +689,7 @@ impl KeepTask {
             spawn(fut.map(move |r| {
                 if let Err(e) = r {
                     if !e.is_not_found() {
-                        error!("revoke lease {} fail: {}", lease_id, e);
+                        error!(target: "xbus::keeper", "revoke lease {} fail: {}", lease_id, e);
                     }
                 }
                 let _ = tx.send(());
@@ -365,8 +704,9 @@ impl KeepTask {
             if let Some(r) = self.lease_future.as_mut().map(|f| Pin::new(f).poll(cx)) {
                 match r {
                     Poll::Ready(Ok(result)) => {
-                        info!("grant lease ok: {:x}", result.lease_id);
-                        if result.new_app_node == Some(true) {
+                        info!(target: "xbus::keeper", "grant lease ok: {:x}", result.lease_id);
+                        self.grant_failures = 0;
+                        if result.is_new_node() {
                             let is_first_online = self.is_first_online;
                             self.online_notifiers
                                 .retain(|tx| tx.unbounded_send(is_first_online).is_ok());
@@ -374,14 +714,26 @@ impl KeepTask {
                         }
                         self.lease_future = None;
                         self.lease_result = Some(result);
-                        self.keep_lease();
-                        self.replug_all(false);
+                        self.keep_lease(None);
+                        self.replug_all(None);
+                        if self.replug_future.is_none() {
+                            // 没有服务需要 plug, 拿到 lease 即就绪
+                            self.mark_ready();
+                        }
                         ct = true;
                     }
                     Poll::Ready(Err(e)) => {
-                        self.new_lease(!e.is_timeout());
+                        let delay = if e.is_timeout() {
+                            None
+                        } else {
+                            self.grant_failures += 1;
+                            Some(e.retry_after().unwrap_or_else(|| {
+                                self.grant_backoff.next_delay(self.grant_failures)
+                            }))
+                        };
+                        self.new_lease(delay);
                         ct = true;
-                        error!("grant lease fail: {}", e);
+                        error!(target: "xbus::keeper", "grant lease fail: {}", e);
                     }
                     Poll::Pending => {}
                 }
@@ -389,46 +741,64 @@ impl KeepTask {
             if let Some(r) = self.replug_future.as_mut().map(|f| Pin::new(f).poll(cx)) {
                 match r {
                     Poll::Ready(Ok(result)) => {
-                        info!("services replugged ok");
+                        info!(
+                            target: "xbus::keeper",
+                            "{} services replugged ok under lease {:x}",
+                            self.replug_count,
+                            result.lease_id
+                        );
                         self.replug_future = None;
+                        self.replug_failures = 0;
+                        self.lease_regrants = 0;
+                        self.mark_ready();
                         for (_, sender) in self.replug_backs.drain() {
                             let _ = sender.send(Ok(()));
                         }
                         if let Some(lease_result) = &mut self.lease_result {
                             if lease_result.lease_id != result.lease_id {
-                                warn!("lease_id changed: {}", result.lease_id);
+                                warn!(target: "xbus::keeper", "lease_id changed: {}", result.lease_id);
                                 lease_result.lease_id = result.lease_id
                             }
                         }
+                        self.emit(KeeperEvent::Replugged {
+                            count: self.replug_count,
+                            lease_id: result.lease_id,
+                        });
                     }
                     Poll::Ready(Err(Error::NotPermitted(_, services))) => {
-                        warn!("not permitted services: {}", services.join(", "));
+                        warn!(target: "xbus::keeper", "not permitted services: {}", services.join(", "));
                         let set: HashSet<String> = HashSet::from_iter(services);
                         self.services.retain(|k, _| {
-                            if set.contains(&k.0) {
-                                error!("plug service not permitted: {}:{}", k.0, k.1);
+                            if set.contains(&k.service) {
+                                error!(target: "xbus::keeper", "plug service not permitted: {}", k);
                                 false
                             } else {
                                 true
                             }
                         });
-                        let keys: Vec<(String, String)> = self
+                        let keys: Vec<ServiceKey> = self
                             .replug_backs
                             .keys()
-                            .filter(|k| set.contains(&k.0))
+                            .filter(|k| set.contains(&k.service))
                             .cloned()
                             .collect();
                         for k in keys {
                             let _ = self.replug_backs.remove(&k).unwrap().send(Err(
-                                Error::NotPermitted("not permitted".to_owned(), vec![k.0]),
+                                Error::NotPermitted("not permitted".to_owned(), vec![k.service]),
                             ));
                         }
-                        self.replug_all(false);
+                        self.replug_all(None);
+                        ct = true;
+                    }
+                    Poll::Ready(Err(e)) if e.is_lease_not_found() => {
+                        let lease_id = self.lease_result.as_ref().map_or(0, |r| r.lease_id);
+                        self.regrant_lease(lease_id);
                         ct = true;
                     }
                     Poll::Ready(Err(e)) => {
-                        error!("services replug failed: {}", e);
-                        self.replug_all(!e.is_timeout());
+                        error!(target: "xbus::keeper", "services replug failed: {}", e);
+                        let delay = self.replug_retry_delay(&e);
+                        self.replug_all(delay);
                         ct = true;
                     }
                     Poll::Pending => {}
@@ -440,16 +810,32 @@ impl KeepTask {
                 .map(|f| Pin::new(f).poll(cx))
             {
                 match r {
-                    Poll::Ready(Ok(_)) => {
-                        self.keep_lease();
+                    Poll::Ready(Ok(result)) => {
+                        self.last_keepalive = Some(Instant::now());
+                        if let (Some(result), Some(lease_result)) = (result, &mut self.lease_result)
+                        {
+                            if result.ttl > 0 && result.ttl != lease_result.ttl {
+                                info!(target: "xbus::keeper", "lease ttl changed: {}", result.ttl);
+                                lease_result.ttl = result.ttl;
+                            }
+                        }
+                        self.keep_lease(None);
+                        ct = true;
+                    }
+                    Poll::Ready(Err(Error::LeaseExpired(lease_id))) => {
+                        // 挂起/休眠后 lease 可能已在服务端过期, 直接重新申请并 replug
+                        warn!(target: "xbus::keeper", "lease {:x} expired, granting a new one", lease_id);
+                        self.new_lease(None);
                         ct = true;
                     }
                     Poll::Ready(Err(e)) => {
-                        error!("keep lease fail: {}", e);
-                        if e.is_timeout() {
-                            self.keep_lease();
+                        error!(target: "xbus::keeper", "keep lease fail: {}", e);
+                        if let Some(retry_after) = e.retry_after() {
+                            self.keep_lease(Some(retry_after));
+                        } else if e.is_timeout() {
+                            self.keep_lease(None);
                         } else {
-                            self.new_lease(false);
+                            self.new_lease(None);
                         }
                         ct = true;
                     }
@@ -460,6 +846,38 @@ impl KeepTask {
     }
 }
 
+fn set_endpoint_config(
+    endpoints: &mut [ServiceEndpoint],
+    address: SocketAddr,
+    config: Option<String>,
+) -> bool {
+    match endpoints.iter_mut().find(|e| e.address == address) {
+        Some(endpoint) => {
+            endpoint.config = config;
+            true
+        }
+        None => false,
+    }
+}
+
+// 单个 endpoint 沿用旧的表单字段, 兼容不支持 endpoints 数组的 server
+fn plug_services(
+    client: &Client,
+    services: &[ServiceDesc],
+    endpoints: &[ServiceEndpoint],
+    lease_id: i64,
+) -> BoxFuture<'static, Result<PlugResult, Error>> {
+    if endpoints.len() == 1 {
+        client
+            .plug_all_services(services, &endpoints[0], Some(lease_id), None)
+            .boxed()
+    } else {
+        client
+            .plug_all_services_with_endpoints(services, endpoints, Some(lease_id), None)
+            .boxed()
+    }
+}
+
 impl Future for KeepTask {
     type Output = ();
 
@@ -486,3 +904,289 @@ impl Future for KeepTask {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mock::MockTransport;
+    use hyper::Method;
+    use serde_json::json;
+
+    const LEASE_ID: i64 = 0x1234;
+
+    fn mock_client() -> (MockTransport, Client) {
+        let transport = MockTransport::new();
+        let lease = json!({"lease_id": LEASE_ID, "ttl": 60});
+        transport.respond(Method::POST, "/api/leases", &lease);
+        transport.respond(Method::POST, "/api/v1/services", &lease);
+        let client = Client::from_responses(Config::new("http://xbus"), transport.clone()).unwrap();
+        (transport, client)
+    }
+
+    fn endpoint(config: Option<&str>) -> ServiceEndpoint {
+        ServiceEndpoint::new("127.0.0.1:8000".parse().unwrap(), config.map(String::from))
+    }
+
+    // 未 start 前 plug 的服务在拿到 lease 后统一 replug
+    async fn start_keeper(
+        client: &Client,
+    ) -> (ServiceKeeper, mpsc::UnboundedReceiver<KeeperEvent>) {
+        let keeper = ServiceKeeper::new(client, Some(60), None, endpoint(None), None);
+        let (tx, rx) = mpsc::unbounded();
+        keeper.notify_events(tx);
+        let service = ServiceDesc::builder("demo.svc", "default").build().unwrap();
+        let plugged = keeper.plug(&service);
+        keeper.start();
+        plugged.await.unwrap();
+        (keeper, rx)
+    }
+
+    #[tokio::test]
+    async fn ready_fails_when_closed_before_ready() {
+        // 没有 /api/leases 的 mock 响应, grant 一直失败
+        let client =
+            Client::from_responses(Config::new("http://xbus"), MockTransport::new()).unwrap();
+        let keeper = ServiceKeeper::new(&client, Some(60), None, endpoint(None), None);
+        keeper.start();
+        let ready = keeper.ready();
+        drop(keeper.close());
+        assert!(ready.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ready_timeout_distinguishes_timeout_and_close() {
+        let client =
+            Client::from_responses(Config::new("http://xbus"), MockTransport::new()).unwrap();
+        let keeper = ServiceKeeper::new(&client, Some(60), None, endpoint(None), None);
+        keeper.start();
+        let err = keeper
+            .ready_timeout(Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+
+        let ready = keeper.ready_timeout(Duration::from_secs(3600));
+        drop(keeper.close());
+        let err = ready.await.unwrap_err();
+        assert!(!err.is_timeout(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn config_only_update_keeps_lease() {
+        let (transport, client) = mock_client();
+        let (keeper, mut events) = start_keeper(&client).await;
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID
+            })
+        );
+
+        keeper.update_endpoint(endpoint(Some(r#"{"weight": 2}"#)));
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID
+            })
+        );
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 1);
+        assert_eq!(transport.count(Method::POST, "/api/v1/services"), 2);
+    }
+
+    #[test]
+    fn endpoint_config_update_keeps_other_endpoints() {
+        let (a, b) = (
+            "127.0.0.1:8000".parse().unwrap(),
+            "127.0.0.1:8001".parse().unwrap(),
+        );
+        let mut endpoints = vec![
+            ServiceEndpoint::new(a, Some(r#"{"weight": 1}"#.to_owned())),
+            ServiceEndpoint::new(b, Some(r#"{"weight": 3}"#.to_owned())),
+        ];
+        assert!(set_endpoint_config(
+            &mut endpoints,
+            a,
+            Some(r#"{"weight": 2}"#.to_owned())
+        ));
+        assert_eq!(endpoints[0].config.as_deref(), Some(r#"{"weight": 2}"#));
+        assert_eq!(endpoints[1].config.as_deref(), Some(r#"{"weight": 3}"#));
+
+        let unknown = "127.0.0.1:9000".parse().unwrap();
+        assert!(!set_endpoint_config(&mut endpoints, unknown, None));
+        assert_eq!(endpoints[1].config.as_deref(), Some(r#"{"weight": 3}"#));
+    }
+
+    #[tokio::test]
+    async fn endpoint_config_update_replugs() {
+        let (transport, client) = mock_client();
+        let (keeper, mut events) = start_keeper(&client).await;
+        events.next().await;
+        keeper.update_endpoint_config(endpoint(None).address, Some(r#"{"weight": 2}"#.to_owned()));
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID
+            })
+        );
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate_plug_guarded_keeps_first() {
+        let (transport, client) = mock_client();
+        let keeper = ServiceKeeper::new(&client, Some(60), None, endpoint(None), None);
+        let (tx, mut events) = mpsc::unbounded();
+        keeper.notify_events(tx);
+        let service = ServiceDesc::builder("demo.svc", "default").build().unwrap();
+        let plugged = keeper.plug_guarded(&service);
+        keeper.start();
+        let _guard = plugged.await.unwrap();
+        events.next().await;
+
+        assert!(keeper.plug_guarded(&service).await.is_err());
+        keeper.update_endpoint(endpoint(Some(r#"{"weight": 2}"#)));
+        // 服务被移除时 replug 会被忽略, 不再有事件
+        let replugged = timeout(Duration::from_secs(5), events.next()).await;
+        assert_eq!(
+            replugged.ok().flatten(),
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID
+            })
+        );
+        let unplugs = transport
+            .requests()
+            .into_iter()
+            .filter(|(m, _)| *m == Method::DELETE)
+            .count();
+        assert_eq!(unplugs, 0);
+    }
+
+    // keepalive 的 mock 响应未配置, 返回 NOT_FOUND 即 lease 已过期
+    #[tokio::test(start_paused = true)]
+    async fn expired_lease_on_keepalive_is_regranted() {
+        let (transport, client) = mock_client();
+        let (_keeper, mut events) = start_keeper(&client).await;
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID
+            })
+        );
+
+        let lease = json!({"lease_id": LEASE_ID + 1, "ttl": 60});
+        transport.respond(Method::POST, "/api/leases", &lease);
+        transport.respond(Method::POST, "/api/v1/services", &lease);
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID + 1
+            })
+        );
+        let keepalive = format!("/api/leases/{}", LEASE_ID);
+        assert_eq!(transport.count(Method::POST, &keepalive), 1);
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lease_not_found_on_replug_regrants_with_backoff() {
+        let (transport, client) = mock_client();
+        let services = "/api/v1/services";
+        transport.respond_error(Method::POST, services, "LEASE_NOT_FOUND", "lease not found");
+        let keeper = ServiceKeeper::new(&client, Some(60), None, endpoint(None), None);
+        let (tx, mut events) = mpsc::unbounded();
+        keeper.notify_events(tx);
+        let service = ServiceDesc::builder("demo.svc", "default").build().unwrap();
+        let plugged = keeper.plug(&service);
+        keeper.start();
+
+        let started = tokio::time::Instant::now();
+        while transport.count(Method::POST, services) == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let lease = json!({"lease_id": LEASE_ID + 1, "ttl": 60});
+        transport.respond(Method::POST, "/api/leases", &lease);
+        transport.respond(Method::POST, services, &lease);
+        plugged.await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(KeeperEvent::Replugged {
+                count: 1,
+                lease_id: LEASE_ID + 1
+            })
+        );
+        // 重新 grant 经过 backoff, 而不是立即重试
+        assert!(started.elapsed() >= Duration::from_secs(GRANT_RETRY_INTERVAL) / 2);
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 2);
+        assert_eq!(transport.count(Method::POST, services), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lease_not_found_on_plug_regrants() {
+        let (transport, client) = mock_client();
+        let (keeper, _events) = start_keeper(&client).await;
+        let path = "/api/v1/services/other.svc";
+        transport.respond_error(Method::POST, path, "LEASE_NOT_FOUND", "lease not found");
+        let other = ServiceDesc::builder("other.svc", "default")
+            .build()
+            .unwrap();
+        let plugged = keeper.plug(&other);
+        while transport.count(Method::POST, path) == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let lease = json!({"lease_id": LEASE_ID + 1, "ttl": 60});
+        transport.respond(Method::POST, "/api/leases", &lease);
+        transport.respond(Method::POST, "/api/v1/services", &lease);
+        plugged.await.unwrap();
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_plug_retried_without_policy() {
+        let (transport, client) = mock_client();
+        let (keeper, _events) = start_keeper(&client).await;
+        let path = "/api/v1/services/other.svc";
+        transport.respond_rate_limited(Method::POST, path, Duration::from_secs(30));
+        let other = ServiceDesc::builder("other.svc", "default")
+            .build()
+            .unwrap();
+        let plugged = keeper.plug(&other);
+        let started = tokio::time::Instant::now();
+        while transport.count(Method::POST, path) == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        transport.respond(
+            Method::POST,
+            path,
+            &json!({"lease_id": LEASE_ID, "ttl": 60}),
+        );
+        plugged.await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(30));
+        assert_eq!(transport.count(Method::POST, path), 2);
+    }
+
+    // 未配置 mock 响应的 plug 返回 NOT_FOUND, 与 lease 无关, 不应重新 grant
+    #[tokio::test]
+    async fn plug_not_found_keeps_lease() {
+        let (transport, client) = mock_client();
+        let (keeper, _events) = start_keeper(&client).await;
+        let other = ServiceDesc::builder("other.svc", "default")
+            .build()
+            .unwrap();
+        let err = keeper.plug(&other).await.unwrap_err();
+        assert!(err.is_not_found(), "{}", err);
+        assert_eq!(
+            transport.count(Method::POST, "/api/v1/services/other.svc"),
+            1
+        );
+        assert_eq!(transport.count(Method::POST, "/api/leases"), 1);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::error::Error;
+use futures::future::{BoxFuture, Shared};
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type SharedResult<T> = Shared<BoxFuture<'static, Result<T, Error>>>;
+
+/// Shares one in-flight request among concurrent callers using the same key.
+pub(crate) struct SingleFlight<T> {
+    pending: Arc<Mutex<HashMap<String, SharedResult<T>>>>,
+}
+
+impl<T> Clone for SingleFlight<T> {
+    fn clone(&self) -> Self {
+        SingleFlight {
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn call<F, Fut>(&self, key: String, f: F) -> SharedResult<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(fut) = pending.get(&key) {
+            return fut.clone();
+        }
+        let (map, done_key) = (self.pending.clone(), key.clone());
+        let fut = f()
+            .map(move |result| {
+                map.lock().unwrap().remove(&done_key);
+                result
+            })
+            .boxed()
+            .shared();
+        pending.insert(key, fut.clone());
+        fut
+    }
+}
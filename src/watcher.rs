@@ -1,17 +1,27 @@
+use crate::backoff::{Backoff, ConstantBackoff};
 use crate::error::Error;
 use crate::RevisionResult;
 use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
+use futures::stream::FusedStream;
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
 use std::time::Duration;
 use tokio::spawn;
-use tokio::time::sleep;
+use tokio::sync::watch;
+use tokio::time::{sleep, Sleep};
 
 const WATCH_DELAY: u64 = 5;
 
+type CloseSender = Mutex<Option<oneshot::Sender<()>>>;
+
+#[must_use = "dropping the handle closes the watch"]
 pub struct WatchHandle {
-    tx: Option<oneshot::Sender<()>>,
+    tx: Arc<CloseSender>,
 }
 
 impl Drop for WatchHandle {
@@ -23,19 +33,49 @@ impl Drop for WatchHandle {
 impl WatchHandle {
     pub(crate) fn pair() -> (oneshot::Receiver<()>, WatchHandle) {
         let (tx, rx) = oneshot::channel();
-        (rx, WatchHandle { tx: Some(tx) })
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        (rx, WatchHandle { tx })
     }
 
     fn close(&mut self) {
-        drop(self.tx.take());
+        drop(self.tx.lock().unwrap().take());
+    }
+}
+
+/// Weak references to the close senders of a client's watches, so that they
+/// can be closed together without keeping dropped ones alive.
+#[derive(Clone, Default)]
+pub(crate) struct WatchRegistry {
+    watches: Arc<Mutex<Vec<Weak<CloseSender>>>>,
+}
+
+impl WatchRegistry {
+    fn register(&self, handle: &WatchHandle) {
+        let mut watches = self.watches.lock().unwrap();
+        watches.retain(|w| w.strong_count() > 0);
+        watches.push(Arc::downgrade(&handle.tx));
+    }
+
+    /// Closes every registered watch still running, returns how many.
+    pub fn close_all(&self) -> usize {
+        let watches = std::mem::take(&mut *self.watches.lock().unwrap());
+        watches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|tx| tx.lock().unwrap().take().is_some())
+            .count()
     }
 }
 
 pub(crate) struct WatchTask<T, WF> {
     close_rx: oneshot::Receiver<()>,
     tx: mpsc::UnboundedSender<T>,
+    buffered: Arc<AtomicUsize>,
 
     last_revision: Option<u64>,
+    backoff: Box<dyn Backoff>,
+    failures: u32,
+    delaying: bool,
     watch: WF,
     watch_future: Pin<Box<dyn Future<Output = Result<Option<T>, Error>> + Send>>,
 }
@@ -48,42 +88,44 @@ where
         + Unpin
         + 'static,
 {
-    pub fn spawn(revision: Option<u64>, watch: WF) -> WatchStream<T> {
+    pub fn spawn(
+        registry: &WatchRegistry,
+        backoff: Option<Box<dyn Backoff>>,
+        revision: Option<u64>,
+        watch: WF,
+    ) -> WatchStream<T> {
         let (tx, rx) = mpsc::unbounded();
         let (close_rx, handle) = WatchHandle::pair();
-        let watch_future = watch(revision);
+        registry.register(&handle);
+        // 首次 watch 调用也放到 poll 中, 由 catch_unwind 兜住 panic;
+        // 立即完成的 Ok(None) 会在首次 poll 时触发 watch(revision)
+        let watch_future = future::ok(None).boxed();
+        let buffered = Arc::new(AtomicUsize::new(0));
         spawn(WatchTask {
             close_rx,
             tx,
+            buffered: buffered.clone(),
             last_revision: revision,
+            backoff: backoff
+                .unwrap_or_else(|| Box::new(ConstantBackoff(Duration::from_secs(WATCH_DELAY)))),
+            failures: 0,
+            delaying: false,
             watch,
             watch_future,
         });
-        WatchStream::new(handle, rx)
+        WatchStream::new(handle, rx, buffered)
     }
 
-    fn watch_once(&mut self, to_delay: bool) {
-        if to_delay {
-            self.watch_future = sleep(Duration::from_secs(WATCH_DELAY))
-                .map(|_| Ok(None))
-                .boxed();
+    fn watch_once(&mut self, delay: Option<Duration>) {
+        self.delaying = delay.is_some();
+        if let Some(delay) = delay {
+            self.watch_future = sleep(delay).map(|_| Ok(None)).boxed();
         } else {
             self.watch_future = (self.watch)(self.last_revision);
         }
     }
-}
-
-impl<T, WF> Future for WatchTask<T, WF>
-where
-    T: RevisionResult + Send + 'static,
-    WF: Fn(Option<u64>) -> Pin<Box<dyn Future<Output = Result<Option<T>, Error>> + Send>>
-        + Send
-        + Unpin
-        + 'static,
-{
-    type Output = ();
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    fn poll_watch(&mut self, cx: &mut Context) -> Poll<()> {
         loop {
             match Pin::new(&mut self.close_rx).poll(cx) {
                 Poll::Pending => {}
@@ -96,21 +138,32 @@ where
                     break;
                 }
                 Poll::Ready(Ok(Some(result))) => {
+                    self.failures = 0;
                     let revision = result.get_revision();
                     if revision > 0 {
                         self.last_revision = Some(revision);
                     }
+                    self.buffered.fetch_add(1, Ordering::Relaxed);
                     if self.tx.unbounded_send(result).is_err() {
                         return Poll::Ready(());
                     }
-                    self.watch_once(false);
+                    self.watch_once(None);
                 }
                 Poll::Ready(Ok(None)) => {
-                    self.watch_once(false);
+                    // 重试等待结束不算成功, 不重置失败次数
+                    if !self.delaying {
+                        self.failures = 0;
+                    }
+                    self.watch_once(None);
                 }
                 Poll::Ready(Err(e)) => {
-                    error!("watch fail: {}", e);
-                    self.watch_once(true);
+                    error!(target: "xbus::watch", "watch fail: {}", e);
+                    self.failures += 1;
+                    let delay = match e.retry_after() {
+                        Some(delay) => delay,
+                        None => self.backoff.next_delay(self.failures),
+                    };
+                    self.watch_once(Some(delay));
                 }
             }
         }
@@ -118,25 +171,286 @@ where
     }
 }
 
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+impl<T, WF> Future for WatchTask<T, WF>
+where
+    T: RevisionResult + Send + 'static,
+    WF: Fn(Option<u64>) -> Pin<Box<dyn Future<Output = Result<Option<T>, Error>> + Send>>
+        + Send
+        + Unpin
+        + 'static,
+{
+    type Output = ();
+
+    // watch 闭包 panic 时记录错误并结束 stream, 而不是让 task 悄悄退出
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        match catch_unwind(AssertUnwindSafe(|| this.poll_watch(cx))) {
+            Ok(r) => r,
+            Err(payload) => {
+                error!(
+                    target: "xbus::watch",
+                    "watch closure panicked, watch stream closed: {}",
+                    panic_message(&*payload)
+                );
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+#[must_use = "dropping the stream closes the watch"]
 pub struct WatchStream<T> {
     handle: WatchHandle,
     rx: mpsc::UnboundedReceiver<T>,
+    closed: bool,
+    received: u64,
+    buffered: Arc<AtomicUsize>,
 }
 
 impl<T> WatchStream<T> {
-    fn new(handle: WatchHandle, rx: mpsc::UnboundedReceiver<T>) -> Self {
-        WatchStream { handle, rx }
+    fn new(
+        handle: WatchHandle,
+        rx: mpsc::UnboundedReceiver<T>,
+        buffered: Arc<AtomicUsize>,
+    ) -> Self {
+        WatchStream {
+            handle,
+            rx,
+            closed: false,
+            received: 0,
+            buffered,
+        }
+    }
+
+    /// Whether the watch task has exited and no more updates will be yielded.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Total number of updates yielded so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Number of updates sent by the watch task but not yet yielded, a
+    /// growing value means the consumer is not keeping up.
+    /// Not tracked after `split`.
+    pub fn buffered_len(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
     }
 
     pub fn split(self) -> (WatchHandle, mpsc::UnboundedReceiver<T>) {
         (self.handle, self.rx)
     }
+
+    /// Collapses bursts of updates, yielding only the latest one after `dur` of quiet.
+    pub fn debounced(self, dur: Duration) -> impl Stream<Item = T> {
+        Debounced {
+            stream: self,
+            dur,
+            latest: None,
+            delay: None,
+            done: false,
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> WatchStream<T> {
+    /// Keeps only the latest update, `None` until the first one, so that any
+    /// number of consumers can clone the receiver and read the current value.
+    /// The watch is closed once every receiver is dropped; receivers see the
+    /// sender dropped when the watch ends.
+    pub fn into_watch_channel(mut self) -> watch::Receiver<Option<T>> {
+        let (tx, rx) = watch::channel(None);
+        spawn(async move {
+            loop {
+                tokio::select! {
+                    item = self.next() => match item {
+                        Some(item) => {
+                            if tx.send(Some(item)).is_err() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        rx
+    }
 }
 
 impl<T> Stream for WatchStream<T> {
     type Item = T;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.rx).poll_next(cx)
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.rx).poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                self.received += 1;
+                self.buffered.fetch_sub(1, Ordering::Relaxed);
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                self.closed = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> FusedStream for WatchStream<T> {
+    fn is_terminated(&self) -> bool {
+        self.closed
+    }
+}
+
+struct Debounced<T> {
+    stream: WatchStream<T>,
+    dur: Duration,
+    latest: Option<T>,
+    delay: Option<Pin<Box<Sleep>>>,
+    done: bool,
+}
+
+impl<T> Unpin for Debounced<T> {}
+
+impl<T> Stream for Debounced<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        while !self.done {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    self.latest = Some(item);
+                    self.delay = Some(Box::pin(sleep(self.dur)));
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+                }
+                Poll::Pending => {
+                    break;
+                }
+            }
+        }
+        if self.latest.is_none() {
+            return if self.done {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+        if !self.done {
+            if let Some(delay) = self.delay.as_mut() {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+        }
+        self.delay = None;
+        Poll::Ready(self.latest.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU32};
+    use std::sync::Once;
+
+    // 记录 xbus::watch 的日志, 确认 panic 被上报而不是 task 悄悄退出
+    struct WatchLog(Mutex<Vec<String>>);
+
+    impl log::Log for WatchLog {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == "xbus::watch"
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static WATCH_LOG: WatchLog = WatchLog(Mutex::new(Vec::new()));
+    static LOG_INIT: Once = Once::new();
+    static LOG_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+    // 其他测试可能已设置 logger, 此时只检查 stream 的结果
+    fn watch_log_installed() -> bool {
+        LOG_INIT.call_once(|| {
+            if log::set_logger(&WATCH_LOG).is_ok() {
+                log::set_max_level(log::LevelFilter::Error);
+                LOG_INSTALLED.store(true, Ordering::SeqCst);
+            }
+        });
+        LOG_INSTALLED.load(Ordering::SeqCst)
+    }
+
+    struct Rev(u64);
+
+    impl RevisionResult for Rev {
+        fn get_revision(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_watch_closes_stream() {
+        let logged = watch_log_installed();
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        // 第一次正常返回, 之后 watch 闭包 panic
+        let mut stream = WatchTask::spawn(&WatchRegistry::default(), None, None, move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) > 0 {
+                panic!("watch closure broken");
+            }
+            future::ok(Some(Rev(1))).boxed()
+        });
+        assert_eq!(stream.next().await.map(|r| r.0), Some(1));
+        assert!(stream.next().await.is_none());
+        assert!(stream.is_closed());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        if logged {
+            let logs = WATCH_LOG.0.lock().unwrap();
+            assert!(
+                logs.iter()
+                    .any(|l| l.contains("watch closure panicked")
+                        && l.contains("watch closure broken")),
+                "{:?}",
+                logs
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn panic_on_first_watch_closes_stream() {
+        let mut stream = WatchTask::spawn(
+            &WatchRegistry::default(),
+            None,
+            None,
+            |_| -> Pin<Box<dyn Future<Output = Result<Option<Rev>, Error>> + Send>> {
+                panic!("first watch broken")
+            },
+        );
+        assert!(stream.next().await.is_none());
+        assert!(stream.is_closed());
     }
 }